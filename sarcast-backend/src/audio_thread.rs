@@ -1,35 +1,79 @@
 // use std::time::Duration;
 
 use rodio::{OutputStream, Sink};
-use std::sync;
+use std::sync::{self, atomic, Arc};
+use std::time::Duration;
 use tokio::sync::mpsc;
 
 use crate::decoder::SymphoniaDecoder;
-use crate::{PlaybackInstructions, ReceivedData};
+use crate::silence::{TrimSilence, Trimming};
+use crate::stretch::{Speed, TimeStretch};
+use crate::{PlaybackInstructions, ReceivedData, SkipAmounts};
 
 pub(crate) fn run(stx: mpsc::Sender<ReceivedData>, mut recv: mpsc::Receiver<PlaybackInstructions>) {
     // Get a output stream handle to the default physical sound device
     let (_stream, stream_handle) = OutputStream::try_default().unwrap();
     let mut sink = Sink::try_new(&stream_handle).unwrap();
     let (mut tx, _) = sync::mpsc::channel();
+    let mut skip = SkipAmounts::default();
+    // Kept from one stream to the next
+    let speed = Speed::default();
+    let trimming = Trimming::default();
+    // How far the current stream has been decoded, in milliseconds
+    let mut position: Option<Arc<atomic::AtomicU64>> = None;
+    let current_position = |position: &Option<Arc<atomic::AtomicU64>>| {
+        position
+            .as_ref()
+            .map(|position| Duration::from_millis(position.load(atomic::Ordering::Relaxed)))
+    };
 
     while let Some(instruction) = recv.blocking_recv() {
         match instruction {
             PlaybackInstructions::Play => sink.play(),
-            PlaybackInstructions::Pause => sink.pause(),
-            PlaybackInstructions::Speed(speed) => sink.set_speed(speed),
-            instruction @ PlaybackInstructions::Seek(_) => {
+            PlaybackInstructions::Pause => {
+                sink.pause();
+                if let Some(position) = current_position(&position) {
+                    let _ = stx.blocking_send(ReceivedData::Paused(position));
+                }
+            }
+            PlaybackInstructions::Stop => {
+                sink.stop();
+                if let Some(position) = current_position(&position.take()) {
+                    let _ = stx.blocking_send(ReceivedData::Stopped(position));
+                }
+            }
+            PlaybackInstructions::Speed(new_speed) => speed.set(new_speed),
+            PlaybackInstructions::TrimSilence(enabled) => trimming.set(enabled),
+            instruction @ (PlaybackInstructions::Seek(_)
+            | PlaybackInstructions::SeekBy(_)
+            | PlaybackInstructions::NextChapter
+            | PlaybackInstructions::PreviousChapter
+            | PlaybackInstructions::JumpToChapter(_)
+            | PlaybackInstructions::SetChapters(_)) => {
                 let _ = tx.send(instruction);
             }
-            PlaybackInstructions::NewStream(file) => {
+            PlaybackInstructions::SkipForward => {
+                let by = i64::try_from(skip.forward.as_millis()).unwrap_or(i64::MAX);
+                let _ = tx.send(PlaybackInstructions::SeekBy(by));
+            }
+            PlaybackInstructions::SkipBack => {
+                let by = i64::try_from(skip.back.as_millis()).unwrap_or(i64::MAX);
+                let _ = tx.send(PlaybackInstructions::SeekBy(-by));
+            }
+            PlaybackInstructions::SetSkipAmounts(amounts) => skip = amounts,
+            PlaybackInstructions::NewStream(file, options) => {
                 sink.stop();
                 sink = Sink::try_new(&stream_handle).unwrap();
                 let (ntx, srx) = sync::mpsc::channel();
                 tx = ntx;
-                let source = SymphoniaDecoder::new(file, stx.clone(), srx).unwrap();
+                let decoded = Arc::new(atomic::AtomicU64::new(0));
+                position = Some(Arc::clone(&decoded));
+                let source =
+                    SymphoniaDecoder::new(file, &options, decoded, stx.clone(), srx).unwrap();
                 // source.convert_samples();
                 // Play the sound directly on the device
-                sink.append(source);
+                let source = TrimSilence::new(source, trimming.clone(), stx.clone());
+                sink.append(TimeStretch::new(source, speed.clone()));
             }
         }
     }
@@ -0,0 +1,44 @@
+use sarcast_data::models::{DownloadRules, Enclosure, Podcast, SelectionPolicy};
+
+use crate::db;
+use crate::download::{self, DownloadManager};
+
+/// Apply `podcast`'s download rules after a refresh: queue the episodes that they want, and
+/// delete the downloads that they no longer need.
+///
+/// Podcasts without any rules are left alone.
+pub(crate) async fn apply(downloads: &DownloadManager, podcast: &Podcast) -> Result<(), db::Error> {
+    let podcast_id = podcast.id();
+    let Some(rules) = db::run(move |con| DownloadRules::for_podcast(con, podcast_id)).await? else {
+        return Ok(());
+    };
+
+    let wanted = {
+        let rules = rules.clone();
+        db::run(move |con| {
+            rules
+                .wanted(con)?
+                .into_iter()
+                .map(|episode| {
+                    let enclosure = Enclosure::choose(con, &episode, &SelectionPolicy::Default)?;
+                    Ok(enclosure.map(|enclosure| (episode, enclosure)))
+                })
+                .filter_map(Result::transpose)
+                .collect::<diesel::QueryResult<Vec<_>>>()
+        })
+        .await?
+    };
+    for (episode, enclosure) in wanted {
+        tracing::info!("Queueing {} from {}", episode.title(), podcast.title());
+        let _ = downloads
+            .enqueue(podcast, episode, &enclosure, false)
+            .await?;
+    }
+
+    let now = chrono::Utc::now().timestamp();
+    let expired = db::run(move |con| rules.expired(con, now)).await?;
+    for episode in expired {
+        download::delete(&episode).await?;
+    }
+    Ok(())
+}
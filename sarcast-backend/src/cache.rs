@@ -0,0 +1,576 @@
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use symphonia::core::io::MediaSource;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::watch;
+
+use sarcast_data::models::{redact_uri, Credentials};
+
+use crate::http::{self, HttpClient, Request, Response};
+
+/// How far ahead of what has been downloaded the reader can be before it's quicker to make a new
+/// request than to wait for the current one to get there.
+const JUMP_DISTANCE: u64 = 512 * 1024;
+
+/// How many times to reconnect in a row before giving up on the stream.
+const MAX_RECONNECTS: u32 = 8;
+/// How long to wait before reconnecting the first time, doubling after each failure.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// The longest to wait before reconnecting.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+/// How long the reader has to wait for bytes before it counts as buffering.
+const BUFFERING_AFTER: Duration = Duration::from_millis(250);
+
+/// Used to give every cache file in this process a different name.
+static NEXT_CACHE: AtomicU64 = AtomicU64::new(0);
+
+/// What has been written to a cache file so far.
+#[derive(Debug, Default)]
+struct Filled {
+    /// Sorted ranges of bytes that are in the file, with no two overlapping or touching
+    ranges: Vec<Range<u64>>,
+    /// The full length of the media, once it is known
+    total: Option<u64>,
+    /// Set once the writer has gone, whether or not it wrote everything
+    closed: bool,
+    /// Why the writer stopped early
+    error: Option<String>,
+}
+
+impl Filled {
+    fn insert(&mut self, range: Range<u64>) {
+        if range.is_empty() {
+            return;
+        }
+        // Swallow every range that overlaps or touches the new one
+        let first = self.ranges.partition_point(|r| r.end < range.start);
+        let last = self.ranges.partition_point(|r| r.start <= range.end);
+        let merged = match self.ranges.get(first..last) {
+            Some([head, .., tail]) => head.start.min(range.start)..tail.end.max(range.end),
+            Some([only]) => only.start.min(range.start)..only.end.max(range.end),
+            _ => range,
+        };
+        let _ = self.ranges.splice(first..last, std::iter::once(merged));
+    }
+
+    /// Find the first range of bytes that is still missing at or after `from`, wrapping around to
+    /// the start of the file if everything after it has arrived
+    fn next_gap(&self, from: u64) -> Option<Range<u64>> {
+        let total = self.total?;
+        let gap_after = |from: u64| {
+            let start = self
+                .ranges
+                .iter()
+                .find(|r| r.contains(&from))
+                .map_or(from, |r| r.end);
+            let end = self
+                .ranges
+                .iter()
+                .map(|r| r.start)
+                .find(|&s| s > start)
+                .unwrap_or(total)
+                .min(total);
+            Some(start..end).filter(|gap| !gap.is_empty())
+        };
+        gap_after(from.min(total)).or_else(|| gap_after(0))
+    }
+
+    /// How many bytes can be read from `position` without waiting
+    fn available(&self, position: u64) -> u64 {
+        self.ranges
+            .iter()
+            .find(|r| r.contains(&position))
+            .map_or(0, |r| r.end - position)
+    }
+}
+
+/// The state shared between the writer and reader of a cache file.
+#[derive(Debug)]
+struct Shared {
+    path: PathBuf,
+    filled: Mutex<Filled>,
+    changed: Condvar,
+    /// Where the reader is waiting for bytes, so that the writer can go and get them
+    wanted: watch::Sender<u64>,
+    /// Whether the reader has been kept waiting for bytes
+    buffering: watch::Sender<bool>,
+}
+
+impl Shared {
+    fn filled(&self) -> MutexGuard<'_, Filled> {
+        self.filled.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn set_buffering(&self, buffering: bool) {
+        let _ = self.buffering.send_if_modified(|current| {
+            let changed = *current != buffering;
+            *current = buffering;
+            changed
+        });
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        // Nothing can read the file once both ends are gone
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Create a new cache file in the temporary directory.
+///
+/// The network writes into the file through the [`CacheWriter`] while the decoder reads from it
+/// through the [`CacheReader`], waiting for any bytes that haven't arrived yet. Only the list of
+/// which bytes have arrived is kept in memory, so long episodes cost no more than short ones.
+/// The file is removed once both ends have been dropped.
+pub(crate) fn create() -> std::io::Result<(CacheWriter, CacheReader)> {
+    let dir = std::env::temp_dir().join("sarcast");
+    std::fs::create_dir_all(&dir)?;
+    let path = dir.join(format!(
+        "{}-{}.cache",
+        std::process::id(),
+        NEXT_CACHE.fetch_add(1, Ordering::Relaxed)
+    ));
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .open(&path)?;
+    let reader = std::fs::File::open(&path)?;
+    let shared = Arc::new(Shared {
+        path,
+        filled: Mutex::default(),
+        changed: Condvar::new(),
+        wanted: watch::channel(0).0,
+        buffering: watch::channel(false).0,
+    });
+    Ok((
+        CacheWriter {
+            file: tokio::fs::File::from_std(file),
+            position: 0,
+            shared: Arc::clone(&shared),
+        },
+        CacheReader {
+            file: reader,
+            position: 0,
+            shared,
+        },
+    ))
+}
+
+/// The end of a cache file that the network writes to.
+#[derive(Debug)]
+pub(crate) struct CacheWriter {
+    file: tokio::fs::File,
+    position: u64,
+    shared: Arc<Shared>,
+}
+
+impl CacheWriter {
+    /// Record the full length of the media, once the server has said what it is
+    pub(crate) fn set_total(&self, total: u64) {
+        self.shared.filled().total = Some(total);
+        self.shared.changed.notify_all();
+    }
+
+    /// Write `bytes` at the current position, and let the reader know that they're there
+    pub(crate) async fn write(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        let _ = self.file.seek(SeekFrom::Start(self.position)).await?;
+        self.file.write_all(bytes).await?;
+        // Make sure that the bytes have actually reached the file before the reader goes looking
+        self.file.flush().await?;
+        let end = self.position + bytes.len() as u64;
+        self.shared.filled().insert(self.position..end);
+        self.shared.changed.notify_all();
+        self.position = end;
+        Ok(())
+    }
+
+    /// Move to where the next write will go
+    fn seek(&mut self, position: u64) {
+        self.position = position;
+    }
+
+    /// Whether the reader is waiting for bytes that the current request won't reach soon
+    fn is_far_from(&self, position: u64) -> bool {
+        self.shared.filled().available(position) == 0
+            && (position < self.position || position > self.position + JUMP_DISTANCE)
+    }
+
+    /// Whether the reader has been dropped, so there's no point writing any more
+    fn is_abandoned(&self) -> bool {
+        Arc::strong_count(&self.shared) == 1
+    }
+
+    /// Give up on the cache, passing `error` on to the reader once it runs out of bytes
+    pub(crate) fn fail(self, error: String) {
+        self.shared.filled().error = Some(error);
+    }
+}
+
+impl Drop for CacheWriter {
+    fn drop(&mut self) {
+        self.shared.filled().closed = true;
+        self.shared.changed.notify_all();
+    }
+}
+
+/// The end of a cache file that the decoder reads from.
+#[derive(Debug)]
+pub(crate) struct CacheReader {
+    file: std::fs::File,
+    position: u64,
+    shared: Arc<Shared>,
+}
+
+impl CacheReader {
+    /// Watch whether reads are waiting on the network
+    pub(crate) fn buffering(&self) -> watch::Receiver<bool> {
+        self.shared.buffering.subscribe()
+    }
+
+    /// Wait until there are bytes to read at the current position, returning how many there are.
+    ///
+    /// Returns `Ok(0)` at the end of the stream.
+    fn wait_for_bytes(&self) -> std::io::Result<u64> {
+        let mut filled = self.shared.filled();
+        let waiting_since = Instant::now();
+        loop {
+            let available = filled.available(self.position);
+            if available > 0 {
+                return Ok(available);
+            }
+            if matches!(filled.total, Some(total) if self.position >= total) {
+                return Ok(0);
+            }
+            if filled.closed {
+                return match &filled.error {
+                    Some(error) => Err(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        error.clone(),
+                    )),
+                    None => Ok(0),
+                };
+            }
+            // Let the writer know where we are, in case it needs to make a new request
+            let position = self.position;
+            let _ = self.shared.wanted.send_if_modified(|wanted| {
+                let changed = *wanted != position;
+                *wanted = position;
+                changed
+            });
+            // Short waits are normal at the edge of the download, so only long ones count as
+            // buffering
+            if waiting_since.elapsed() >= BUFFERING_AFTER {
+                self.shared.set_buffering(true);
+            }
+            filled = self
+                .shared
+                .changed
+                .wait_timeout(filled, BUFFERING_AFTER)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+    }
+}
+
+impl Read for CacheReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let available = self.wait_for_bytes();
+        self.shared.set_buffering(false);
+        let available = available?;
+        if available == 0 {
+            return Ok(0);
+        }
+        let length = usize::try_from(available).map_or(buf.len(), |a| a.min(buf.len()));
+        let _ = self.file.seek(SeekFrom::Start(self.position))?;
+        let read = self.file.read(&mut buf[..length])?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for CacheReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(start) => Some(start),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            SeekFrom::End(offset) => {
+                let total = self.shared.filled().total.ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::Unsupported,
+                        "The length of the stream isn't known yet",
+                    )
+                })?;
+                total.checked_add_signed(offset)
+            }
+        };
+        self.position = position.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Can't seek before the start of the stream",
+            )
+        })?;
+        Ok(self.position)
+    }
+}
+
+impl MediaSource for CacheReader {
+    fn is_seekable(&self) -> bool {
+        true
+    }
+    fn byte_len(&self) -> Option<u64> {
+        self.shared.filled().total
+    }
+}
+
+/// Start streaming `url` into a new cache file, returning the reader for it straight away.
+/// Every request for it is made with `credentials`.
+///
+/// If the server supports `Range` requests, seeking to somewhere that hasn't been downloaded yet
+/// starts a new request from there, rather than waiting for the download to get there. The
+/// parts that were skipped are filled in afterwards.
+///
+/// Connections that stall or drop are reconnected from where they left off, while the reader
+/// waits and reports that it's buffering.
+pub(crate) async fn stream(
+    client: &HttpClient,
+    url: reqwest::Url,
+    credentials: Option<Credentials>,
+) -> Result<CacheReader, Box<dyn std::error::Error + Send + Sync>> {
+    let client = client.clone();
+    let (mut writer, reader) = create()?;
+    let response = client
+        .send(Request::get(&url, credentials.as_ref()))
+        .await?;
+    if http::requires_auth(response.status()) {
+        return Err(format!("Authentication required ({})", response.status()).into());
+    }
+    let response = response.error_for_status()?;
+    if let Some(total) = response.content_length() {
+        writer.set_total(total);
+    }
+    let seekable = response.content_length().is_some()
+        && matches!(
+            response.headers().get(reqwest::header::ACCEPT_RANGES),
+            Some(value) if value.as_bytes().eq_ignore_ascii_case(b"bytes")
+        );
+    let _ = tokio::task::spawn(async move {
+        let (url, credentials) = (&url, credentials.as_ref());
+        if let Err(e) = fill(&client, url, credentials, &mut writer, response, seekable).await {
+            tracing::warn!("Streaming stopped early: {}", e);
+            writer.fail(e.to_string());
+        }
+    });
+    Ok(reader)
+}
+
+/// How copying a response into the cache came to an end.
+enum Copied {
+    /// Every byte of the response was written
+    Ended,
+    /// The reader wants bytes far from where the response is up to
+    Jump(u64),
+    /// The reader has gone, so nothing more is needed
+    Abandoned,
+    /// The connection stalled or dropped before the response was finished
+    Dropped(String),
+}
+
+/// Write `response` into the cache, then keep requesting whatever is still missing until the
+/// whole file is there or the reader goes away.
+async fn fill(
+    client: &HttpClient,
+    url: &reqwest::Url,
+    credentials: Option<&Credentials>,
+    writer: &mut CacheWriter,
+    mut response: Response,
+    mut seekable: bool,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut wanted = writer.shared.wanted.subscribe();
+    let mut end = writer.shared.filled().total;
+    let mut reconnects = Reconnects::default();
+    loop {
+        let start = writer.position;
+        let copied = copy(&mut response, writer, &mut wanted, seekable, end).await?;
+        // A connection that got somewhere means the one before it recovered
+        if writer.position != start {
+            reconnects.recovered();
+        }
+        let from = match copied {
+            Copied::Abandoned => return Ok(()),
+            Copied::Ended if !seekable => return Ok(()),
+            // Fetch the next missing part, starting from wherever the reader is
+            Copied::Ended => *wanted.borrow(),
+            Copied::Jump(position) => position,
+            Copied::Dropped(error) => {
+                let Some(delay) = reconnects.failed() else {
+                    return Err(error.into());
+                };
+                tracing::warn!("Stream dropped ({}), reconnecting in {:?}", error, delay);
+                tokio::time::sleep(delay).await;
+                // Without ranges, the only option is to start again
+                if seekable {
+                    writer.position
+                } else {
+                    0
+                }
+            }
+        };
+        if writer.is_abandoned() {
+            return Ok(());
+        }
+
+        let range = if seekable {
+            match writer.shared.filled().next_gap(from) {
+                Some(gap) => Some(gap),
+                None => return Ok(()),
+            }
+        } else {
+            None
+        };
+        // Give up the old connection first, so that it doesn't count against the host's limit
+        drop(response);
+        response = loop {
+            match request(client, url, credentials, range.as_ref()).await {
+                Ok(response) => break response,
+                Err(e) => {
+                    let Some(delay) = reconnects.failed() else {
+                        return Err(e);
+                    };
+                    tracing::warn!("Failed to reconnect ({}), retrying in {:?}", e, delay);
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        };
+        match range {
+            Some(range) if response.status() == reqwest::StatusCode::PARTIAL_CONTENT => {
+                writer.seek(range.start);
+                end = Some(range.end);
+            }
+            _ => {
+                // The server ignored the range after all, so just read it all from the start
+                writer.seek(0);
+                seekable = false;
+                end = response.content_length();
+            }
+        }
+    }
+}
+
+/// Copy `response` into the cache until it ends, or something means it should be abandoned.
+///
+/// `end` is where the response should stop, if it's known, so that connections closed early
+/// aren't mistaken for the end of the stream.
+async fn copy(
+    response: &mut Response,
+    writer: &mut CacheWriter,
+    wanted: &mut watch::Receiver<u64>,
+    seekable: bool,
+    end: Option<u64>,
+) -> std::io::Result<Copied> {
+    loop {
+        tokio::select! {
+            chunk = response.chunk() => match chunk {
+                Ok(Some(chunk)) => {
+                    writer.write(&chunk).await?;
+                    if writer.is_abandoned() {
+                        return Ok(Copied::Abandoned);
+                    }
+                }
+                Ok(None) => {
+                    return Ok(match end {
+                        Some(end) if writer.position < end => Copied::Dropped(format!(
+                            "Connection closed after {} of {} bytes",
+                            writer.position, end
+                        )),
+                        _ => Copied::Ended,
+                    });
+                }
+                Err(e) => return Ok(Copied::Dropped(e.to_string())),
+            },
+            Ok(()) = wanted.changed(), if seekable => {
+                let position = *wanted.borrow_and_update();
+                if writer.is_far_from(position) {
+                    return Ok(Copied::Jump(position));
+                }
+            }
+        }
+    }
+}
+
+async fn request(
+    client: &HttpClient,
+    url: &reqwest::Url,
+    credentials: Option<&Credentials>,
+    range: Option<&Range<u64>>,
+) -> Result<Response, http::Error> {
+    let mut request = Request::get(url, credentials);
+    if let Some(range) = range {
+        tracing::debug!(
+            "Requesting bytes {}-{} of {}",
+            range.start,
+            range.end - 1,
+            redact_uri(url.as_str())
+        );
+        request = request.header(
+            reqwest::header::RANGE,
+            format!("bytes={}-{}", range.start, range.end - 1),
+        );
+    }
+    Ok(client.send(request).await?.error_for_status()?)
+}
+
+/// Counts how many times in a row a stream has failed to connect or dropped without getting
+/// anywhere, to back off between attempts and give up once there have been too many.
+#[derive(Debug, Default)]
+struct Reconnects {
+    failures: u32,
+}
+
+impl Reconnects {
+    /// Count a failure, returning how long to wait before reconnecting, or `None` if it's time to
+    /// give up
+    fn failed(&mut self) -> Option<Duration> {
+        self.failures += 1;
+        (self.failures <= MAX_RECONNECTS).then(|| {
+            (RECONNECT_DELAY * 2_u32.saturating_pow(self.failures - 1)).min(MAX_RECONNECT_DELAY)
+        })
+    }
+
+    /// Start counting again, once a connection has written something
+    fn recovered(&mut self) {
+        self.failures = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub(crate) fn reconnects_reset_after_progress() {
+        let mut reconnects = Reconnects::default();
+        // Far more drops than the limit, but each one resumed and wrote something
+        for _ in 0..MAX_RECONNECTS * 4 {
+            assert_eq!(reconnects.failed(), Some(RECONNECT_DELAY));
+            reconnects.recovered();
+        }
+
+        let delays: Vec<_> = (0..=MAX_RECONNECTS).map(|_| reconnects.failed()).collect();
+        assert_eq!(delays[1], Some(RECONNECT_DELAY * 2));
+        assert_eq!(
+            delays[MAX_RECONNECTS as usize - 1],
+            Some(MAX_RECONNECT_DELAY)
+        );
+        assert_eq!(delays[MAX_RECONNECTS as usize], None);
+    }
+}
@@ -0,0 +1,195 @@
+use std::time::Duration;
+
+use sarcast_data::chapters::{self, JsonChapter, ShowNoteChapter};
+use sarcast_data::models::Credentials;
+use symphonia::core::meta::{StandardTagKey, TableOfContents, TableOfContentsItem, Tag};
+
+use crate::http::{self, HttpClient, Request};
+
+/// How far into a chapter "previous chapter" goes back to the start of the same chapter, rather
+/// than to the one before it
+const RESTART_CHAPTER_AFTER: Duration = Duration::from_secs(3);
+
+/// One chapter of the stream that is playing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chapter {
+    pub title: Option<String>,
+    pub start: Duration,
+    /// Where the chapter ends, if the media says
+    pub end: Option<Duration>,
+    /// The URL of an image to show during the chapter
+    pub image: Option<String>,
+    /// A web page that the chapter is about
+    pub url: Option<String>,
+    /// Whether the chapter is only a marker, such as an image change, that isn't navigated to
+    pub hidden: bool,
+    /// Whether the chapter was made up from the show notes, rather than published as a chapter
+    pub derived: bool,
+}
+
+/// The chapters of the stream that is playing, in the order that they play
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Chapters(Vec<Chapter>);
+
+impl Chapters {
+    /// Flatten a table of contents, including any nested ones, into a list of chapters
+    pub(crate) fn from_table_of_contents(table_of_contents: &TableOfContents) -> Self {
+        fn flatten(table_of_contents: &TableOfContents, chapters: &mut Vec<Chapter>) {
+            for item in &table_of_contents.items {
+                match item {
+                    TableOfContentsItem::Chapter(chapter) => {
+                        let start = Duration::from_millis(u64::from(chapter.start_ms));
+                        let end = Duration::from_millis(u64::from(chapter.end_ms));
+                        chapters.push(Chapter {
+                            title: title(&chapter.tags),
+                            start,
+                            end: Some(end).filter(|end| *end > start),
+                            image: None,
+                            url: None,
+                            hidden: false,
+                            derived: false,
+                        });
+                    }
+                    TableOfContentsItem::TableOfContents(nested) => flatten(nested, chapters),
+                }
+            }
+        }
+
+        let mut chapters = Vec::new();
+        flatten(table_of_contents, &mut chapters);
+        chapters.sort_by_key(|chapter| chapter.start);
+        Chapters(chapters)
+    }
+
+    /// Read the chapters from a Podcasting 2.0 JSON chapters file
+    pub(crate) fn from_json(chapters: &[JsonChapter]) -> Self {
+        Chapters(
+            chapters
+                .iter()
+                .map(|chapter| Chapter {
+                    title: chapter.title().map(str::to_owned),
+                    start: chapter.start(),
+                    end: chapter.end(),
+                    image: chapter.image().map(str::to_owned),
+                    url: chapter.url().map(str::to_owned),
+                    hidden: !chapter.in_table_of_contents(),
+                    derived: false,
+                })
+                .collect(),
+        )
+    }
+
+    /// Make up chapters from the timestamps listed in an episode's show notes, each running
+    /// until the next one starts
+    pub(crate) fn from_show_notes(chapters: &[ShowNoteChapter]) -> Self {
+        let ends = chapters
+            .iter()
+            .skip(1)
+            .map(|chapter| Some(chapter.start()))
+            .chain([None]);
+        chapters
+            .iter()
+            .zip(ends)
+            .map(|(chapter, end)| Chapter {
+                title: Some(chapter.title().to_owned()),
+                start: chapter.start(),
+                end,
+                image: None,
+                url: None,
+                hidden: false,
+                derived: true,
+            })
+            .collect()
+    }
+
+    /// Combine the chapters embedded in the media with `published` ones from the feed.
+    ///
+    /// The published chapters win whenever there are any, as they can be corrected after the
+    /// episode is released and carry images and links. The two lists are never interleaved,
+    /// since they rarely agree on where chapters start.
+    pub(crate) fn merge(self, published: Chapters) -> Chapters {
+        if published.is_empty() {
+            self
+        } else {
+            published
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Chapter> {
+        self.0.get(index)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Chapter> {
+        self.0.iter()
+    }
+
+    /// The index of the chapter that is playing at `position`, which is the last one to have
+    /// started by then
+    pub fn at(&self, position: Duration) -> Option<usize> {
+        self.0
+            .partition_point(|chapter| chapter.start <= position)
+            .checked_sub(1)
+    }
+
+    /// The index of the chapter after the one playing at `position`, skipping hidden ones
+    pub(crate) fn next(&self, position: Duration) -> Option<usize> {
+        self.0
+            .iter()
+            .position(|chapter| !chapter.hidden && chapter.start > position)
+    }
+
+    /// The index of the chapter to go back to from `position`, skipping hidden ones. This is the
+    /// start of the current chapter unless playback has only just got into it.
+    pub(crate) fn previous(&self, position: Duration) -> Option<usize> {
+        let current = self
+            .0
+            .iter()
+            .rposition(|chapter| !chapter.hidden && chapter.start <= position)?;
+        if position.saturating_sub(self.0[current].start) > RESTART_CHAPTER_AFTER {
+            return Some(current);
+        }
+        self.0[..current]
+            .iter()
+            .rposition(|chapter| !chapter.hidden)
+            .or(Some(current))
+    }
+}
+
+impl FromIterator<Chapter> for Chapters {
+    fn from_iter<I: IntoIterator<Item = Chapter>>(chapters: I) -> Self {
+        let mut chapters: Vec<Chapter> = chapters.into_iter().collect();
+        chapters.sort_by_key(|chapter| chapter.start);
+        Chapters(chapters)
+    }
+}
+
+/// Fetch the JSON chapters file at `uri`
+pub(crate) async fn fetch(
+    client: &HttpClient,
+    uri: &str,
+    credentials: Option<&Credentials>,
+) -> Result<Chapters, http::Error> {
+    let json = client
+        .send(Request::get(uri, credentials))
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    Ok(Chapters::from_json(&chapters::parse(&json)?))
+}
+
+/// The title of a chapter, from its `TIT2` frame
+fn title(tags: &[Tag]) -> Option<String> {
+    tags.iter()
+        .find(|tag| tag.std_key == Some(StandardTagKey::TrackTitle) || tag.key == "TIT2")
+        .map(|tag| tag.value.to_string())
+        .filter(|title| !title.trim().is_empty())
+}
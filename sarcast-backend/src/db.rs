@@ -0,0 +1,17 @@
+use diesel::prelude::*;
+
+pub(crate) type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// Run `query` against the database on the blocking thread pool.
+pub(crate) async fn run<T, E, F>(query: F) -> Result<T, Error>
+where
+    T: Send + 'static,
+    E: Into<Error>,
+    F: FnOnce(&mut SqliteConnection) -> Result<T, E> + Send + 'static,
+{
+    tokio::task::spawn_blocking(move || {
+        let mut con = sarcast_data::connection().get()?;
+        query(&mut con).map_err(Into::into)
+    })
+    .await?
+}
@@ -8,21 +8,29 @@ use symphonia::{
         io::{MediaSource, MediaSourceStream},
         meta::MetadataOptions,
         probe::Hint,
-        units::Time,
+        units::{Time, TimeBase},
     },
     default::get_probe,
 };
 
 use rodio::{decoder::DecoderError, Source};
 
-use std::sync::mpsc;
+use std::io::{Seek, SeekFrom};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
 use tokio::sync::mpsc::Sender;
 
+use crate::chapters::Chapters;
+use crate::mp4::ChapterScan;
+
 // Decoder errors are not considered fatal.
 // The correct action is to just get a new packet and try again.
 // But a decode error in more than 3 consecutive packets is fatal.
 const MAX_DECODE_ERRORS: usize = 3;
 
+/// How far playback moves between progress events, so that the UI isn't sent one per packet
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
 pub(crate) struct SymphoniaDecoder {
     decoder: Box<dyn Decoder>,
     current_frame_offset: usize,
@@ -30,24 +38,46 @@ pub(crate) struct SymphoniaDecoder {
     pub(crate) format: Box<dyn FormatReader>,
     buffer: SampleBuffer<i16>,
     spec: SignalSpec,
+    duration: Option<Duration>,
+    time_base: Option<TimeBase>,
+    /// The last position that was decoded, in milliseconds, shared with the audio thread
+    position: Arc<AtomicU64>,
+    reported: Option<Duration>,
+    chapters: Chapters,
+    /// The index of the chapter that is playing
+    chapter: Option<usize>,
     tx: Sender<crate::ReceivedData>,
     rx: mpsc::Receiver<crate::PlaybackInstructions>,
 }
 
 impl SymphoniaDecoder {
-    pub(crate) fn new<MS: MediaSource + 'static>(
-        ms: MS,
+    pub(crate) fn new(
+        ms: Box<dyn MediaSource>,
+        options: &crate::StreamOptions,
+        position: Arc<AtomicU64>,
         tx: Sender<crate::ReceivedData>,
         rx: mpsc::Receiver<crate::PlaybackInstructions>,
     ) -> Result<Self, DecoderError> {
-        let mss = MediaSourceStream::new(Box::new(ms) as Box<dyn MediaSource>, Default::default());
-        match SymphoniaDecoder::init(mss, tx, rx) {
+        let mut ms = ms;
+        // Symphonia doesn't read the chapters of MP4 files, so look for them before it does
+        let mut mp4_chapters = Chapters::default();
+        if ms.is_seekable() && options.mp4_chapters != ChapterScan::Skip {
+            mp4_chapters =
+                crate::mp4::read_chapters(&mut ms, options.mp4_chapters).unwrap_or_else(|e| {
+                    tracing::debug!("Failed to read MP4 chapters: {}", e);
+                    Chapters::default()
+                });
+            let _ = ms
+                .seek(SeekFrom::Start(0))
+                .map_err(|e| DecoderError::IoError(e.to_string()))?;
+        }
+        let mss = MediaSourceStream::new(ms, Default::default());
+        match SymphoniaDecoder::init(mss, options, mp4_chapters, position, tx, rx) {
             Err(e) => match e {
                 Error::IoError(e) => Err(DecoderError::IoError(e.to_string())),
                 Error::DecodeError(e) => Err(DecoderError::DecodeError(e)),
-                Error::SeekError(_) => {
-                    unreachable!("Seek errors should not occur during initialization")
-                }
+                // Initialization seeks to where the episode was left off, which a truncated stream can fail
+                e @ Error::SeekError(_) => Err(DecoderError::IoError(e.to_string())),
                 Error::Unsupported(_) => Err(DecoderError::UnrecognizedFormat),
                 Error::LimitError(e) => Err(DecoderError::LimitError(e)),
                 Error::ResetRequired => Err(DecoderError::ResetRequired),
@@ -57,6 +87,28 @@ impl SymphoniaDecoder {
         }
     }
 
+    /// Seek to `start`, or back to the beginning if the stream doesn't reach it
+    fn resume(format: &mut dyn FormatReader, decoder: &mut dyn Decoder, start: Duration) {
+        let seek_to = |time: f64| formats::SeekTo::Time {
+            time: Time::from(time),
+            track_id: None,
+        };
+        match format.seek(formats::SeekMode::Accurate, seek_to(start.as_secs_f64())) {
+            Ok(_) => decoder.reset(),
+            Err(e) => {
+                // The saved position may be past the end of a truncated stream
+                tracing::warn!(
+                    "Failed to resume at {:?}, starting from the beginning: {}",
+                    start,
+                    e
+                );
+                if format.seek(formats::SeekMode::Coarse, seek_to(0.0)).is_ok() {
+                    decoder.reset();
+                }
+            }
+        }
+    }
+
     #[allow(dead_code)]
     pub(crate) fn into_inner(self: Box<Self>) -> MediaSourceStream {
         self.format.into_inner()
@@ -64,16 +116,32 @@ impl SymphoniaDecoder {
 
     fn init(
         mss: MediaSourceStream,
+        options: &crate::StreamOptions,
+        mp4_chapters: Chapters,
+        position: Arc<AtomicU64>,
         tx: Sender<crate::ReceivedData>,
         rx: mpsc::Receiver<crate::PlaybackInstructions>,
     ) -> symphonia::core::errors::Result<Option<SymphoniaDecoder>> {
-        let hint = Hint::new();
         let format_opts: FormatOptions = Default::default();
         let metadata_opts: MetadataOptions = Default::default();
-        let mut probed = get_probe().format(&hint, mss, &format_opts, &metadata_opts)?;
+        let mut probed = get_probe().format(&options.hint, mss, &format_opts, &metadata_opts)?;
+        let mut chapters = mp4_chapters;
         if let Some(mut maybe_metadata) = probed.metadata.get() {
-            let metadata = maybe_metadata.skip_to_latest().cloned();
-            let _ = tx.blocking_send(crate::ReceivedData::NewMetadata(metadata.unwrap()));
+            let metadata = maybe_metadata.skip_to_latest().cloned().unwrap();
+            if let Some(table_of_contents) = metadata.table_of_contents() {
+                let embedded = Chapters::from_table_of_contents(table_of_contents);
+                if !embedded.is_empty() {
+                    chapters = embedded;
+                }
+            }
+            let _ = tx.blocking_send(crate::ReceivedData::NewMetadata(metadata));
+        }
+        // Only make do with the show notes if the media has no chapters of its own
+        if chapters.is_empty() {
+            chapters = options.chapters.clone();
+        }
+        if !chapters.is_empty() {
+            let _ = tx.blocking_send(crate::ReceivedData::Chapters(chapters.clone()));
         }
 
         let stream = match probed.format.default_track() {
@@ -81,6 +149,16 @@ impl SymphoniaDecoder {
             None => return Ok(None),
         };
 
+        // VBR MP3s without a Xing header don't say how many frames they have, so the feed has to do
+        let time_base = stream.codec_params.time_base;
+        let duration = match (stream.codec_params.n_frames, time_base) {
+            (Some(n_frames), Some(time_base)) => Some(to_duration(time_base.calc_time(n_frames))),
+            _ => options.duration,
+        };
+        if let Some(duration) = duration {
+            let _ = tx.blocking_send(crate::ReceivedData::Duration(duration));
+        }
+
         let mut decoder = symphonia::default::get_codecs().make(
             &stream.codec_params,
             &DecoderOptions {
@@ -89,6 +167,11 @@ impl SymphoniaDecoder {
             },
         )?;
 
+        // Pick up where the episode was left off
+        if !options.start.is_zero() {
+            SymphoniaDecoder::resume(probed.format.as_mut(), decoder.as_mut(), options.start);
+        }
+
         let mut decode_errors: usize = 0;
         let (packet, decoded) = loop {
             let current_frame = probed.format.next_packet()?;
@@ -117,11 +200,117 @@ impl SymphoniaDecoder {
             packet,
             buffer,
             spec,
+            duration,
+            time_base,
+            position,
+            reported: None,
+            chapters,
+            chapter: None,
             tx,
             rx,
         }))
     }
 
+    /// Build a probe `Hint` from what the feed says about the media.
+    ///
+    /// Raw streams like AAC/ADTS can't be reliably detected from their contents alone, so this
+    /// lets the probe start with the right format.
+    pub(crate) fn hint(mime_type: Option<&str>, extension: Option<&str>) -> Hint {
+        let mut hint = Hint::new();
+        if let Some(mime_type) = mime_type {
+            let _ = hint.mime_type(mime_type);
+        }
+        if let Some(extension) = extension {
+            let _ = hint.with_extension(extension);
+        }
+        hint
+    }
+
+    /// The last position that was decoded
+    fn decoded_position(&self) -> Duration {
+        Duration::from_millis(self.position.load(Ordering::Relaxed))
+    }
+
+    fn set_position(&self, position: Duration) {
+        self.position.store(
+            u64::try_from(position.as_millis()).unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Seek to `to`, or to the end if it is past the end, and report where playback ended up
+    fn seek(&mut self, to: Duration) {
+        let to = match self.duration {
+            Some(duration) => to.min(duration),
+            None => to,
+        };
+        let seeked = self.format.seek(
+            formats::SeekMode::Accurate,
+            formats::SeekTo::Time {
+                time: Time::from(to.as_secs_f64()),
+                track_id: None,
+            },
+        );
+        let result = match seeked {
+            Ok(seeked) => {
+                self.decoder.reset();
+                let position = self.time_base.map_or(to, |time_base| {
+                    to_duration(time_base.calc_time(seeked.actual_ts))
+                });
+                self.set_position(position);
+                // Let the UI catch up straight away
+                self.reported = None;
+                Ok(position)
+            }
+            Err(e) => Err(e.to_string()),
+        };
+        let _ = self.tx.try_send(crate::ReceivedData::Seeked(result));
+    }
+
+    /// Seek to the start of the chapter with the given index, or report that there isn't one
+    fn seek_to_chapter(&mut self, index: Option<usize>) {
+        match index.and_then(|index| self.chapters.get(index)) {
+            Some(chapter) => self.seek(chapter.start),
+            None => {
+                let _ = self.tx.try_send(crate::ReceivedData::Seeked(Err(
+                    "There is no such chapter".to_owned(),
+                )));
+            }
+        }
+    }
+
+    /// Let the audio thread and the UI know how far playback has got, at most once per
+    /// [`PROGRESS_INTERVAL`], and whenever it moves into another chapter
+    fn report_position(&mut self, ts: u64) {
+        let Some(time_base) = self.time_base else {
+            return;
+        };
+        let position = to_duration(time_base.calc_time(ts));
+        self.set_position(position);
+        let chapter = self.chapters.at(position);
+        if chapter != self.chapter {
+            self.chapter = chapter;
+            if let Some(index) = chapter {
+                let _ = self.tx.try_send(crate::ReceivedData::ChapterChanged(
+                    index,
+                    self.chapters.get(index).cloned().unwrap(),
+                ));
+            }
+        }
+        let due = match self.reported {
+            Some(reported) => position.abs_diff(reported) >= PROGRESS_INTERVAL,
+            None => true,
+        };
+        if due
+            && self
+                .tx
+                .try_send(crate::ReceivedData::NewTimestamp(position))
+                .is_ok()
+        {
+            self.reported = Some(position);
+        }
+    }
+
     #[inline]
     fn get_buffer(decoded: AudioBufferRef<'_>, spec: &SignalSpec) -> SampleBuffer<i16> {
         let duration = decoded.capacity() as u64;
@@ -131,6 +320,10 @@ impl SymphoniaDecoder {
     }
 }
 
+fn to_duration(time: Time) -> Duration {
+    Duration::from_secs(time.seconds) + Duration::from_secs_f64(time.frac)
+}
+
 impl Source for SymphoniaDecoder {
     #[inline]
     fn current_frame_len(&self) -> Option<usize> {
@@ -149,7 +342,7 @@ impl Source for SymphoniaDecoder {
 
     #[inline]
     fn total_duration(&self) -> Option<Duration> {
-        None
+        self.duration
     }
 }
 
@@ -159,20 +352,39 @@ impl Iterator for SymphoniaDecoder {
     #[inline]
     fn next(&mut self) -> Option<i16> {
         if self.current_frame_offset == self.buffer.len() {
-            match self.rx.try_recv() {
-                Ok(instruction) => {
-                    if let crate::PlaybackInstructions::Seek(to) = instruction {
-                        let seconds = to as f64 / 1000.0;
-                        let _ = self.format.seek(
-                            formats::SeekMode::Accurate,
-                            formats::SeekTo::Time {
-                                time: Time::from(seconds),
-                                track_id: None,
-                            },
-                        );
+            // Apply every seek that came in while the last packet played, in order
+            while let Ok(instruction) = self.rx.try_recv() {
+                match instruction {
+                    crate::PlaybackInstructions::Seek(to) => self.seek(Duration::from_millis(to)),
+                    crate::PlaybackInstructions::SeekBy(by) => {
+                        let offset = Duration::from_millis(by.unsigned_abs());
+                        let position = self.decoded_position();
+                        self.seek(if by < 0 {
+                            position.saturating_sub(offset)
+                        } else {
+                            position + offset
+                        });
+                    }
+                    crate::PlaybackInstructions::NextChapter => {
+                        let next = self.chapters.next(self.decoded_position());
+                        self.seek_to_chapter(next);
+                    }
+                    crate::PlaybackInstructions::PreviousChapter => {
+                        let previous = self.chapters.previous(self.decoded_position());
+                        self.seek_to_chapter(previous);
                     }
+                    crate::PlaybackInstructions::JumpToChapter(index) => {
+                        self.seek_to_chapter(Some(index));
+                    }
+                    crate::PlaybackInstructions::SetChapters(published) => {
+                        self.chapters = std::mem::take(&mut self.chapters).merge(published);
+                        self.chapter = None;
+                        let _ = self
+                            .tx
+                            .try_send(crate::ReceivedData::Chapters(self.chapters.clone()));
+                    }
+                    _ => {}
                 }
-                Err(_) => {}
             }
 
             let mut decode_errors: usize = 0;
@@ -198,9 +410,7 @@ impl Iterator for SymphoniaDecoder {
             self.spec = *decoded.spec();
             self.buffer = SymphoniaDecoder::get_buffer(decoded, &self.spec);
             self.current_frame_offset = 0;
-            let _ = self
-                .tx
-                .try_send(crate::ReceivedData::NewTimestamp(packet.ts));
+            self.report_position(packet.ts);
             self.packet = packet;
         }
 
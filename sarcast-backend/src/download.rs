@@ -0,0 +1,614 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use sarcast_data::models::{redact_uri, Credentials, Download, Enclosure, Episode, Podcast};
+use sarcast_data::{media, naming, storage};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{broadcast, Mutex, Notify, Semaphore};
+
+use crate::db;
+use crate::http::{self, HttpClient, Request};
+use crate::integrity::Integrity;
+use crate::limits::{DownloadLimits, RateLimiter};
+
+/// How many bytes are downloaded between progress events.
+const PROGRESS_INTERVAL: u64 = 256 * 1024;
+/// How many progress events pass between saving the progress to the database.
+const SAVE_EVERY: u32 = 16;
+/// How many times a download that ends early is resumed before it counts as a failure.
+const MAX_RESUMES: u32 = 3;
+/// How much more room is set aside at a time for a download that turns out to be bigger than
+/// was expected.
+const RESERVE_STEP: u64 = 16 * 1024 * 1024;
+
+/// Something that happened to the download of an episode.
+#[derive(Debug, Clone)]
+pub(crate) struct DownloadEvent {
+    pub(crate) podcast_id: i32,
+    pub(crate) episode_title: String,
+    pub(crate) status: DownloadStatus,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum DownloadStatus {
+    Started,
+    Progress {
+        downloaded: u64,
+        total: Option<u64>,
+    },
+    Completed {
+        path: PathBuf,
+    },
+    Failed {
+        error: String,
+        will_retry: bool,
+    },
+    /// The download window closed, so the download will carry on when it next opens
+    Paused,
+}
+
+/// How a single request for the rest of a download ended
+enum Transfer {
+    /// The response ended, leaving this many bytes of the server's total in the `.part` file
+    Ended(u64, Option<u64>),
+    /// The download window closed part way through
+    Paused,
+    /// The download turned out to be too big to fit within the storage quotas
+    NoRoom,
+}
+
+/// How a download came to an end, other than by failing
+enum Outcome {
+    /// The whole download was saved to its path, and whether its length or integrity hash was
+    /// checked
+    Completed { verified: bool },
+    /// The download window closed, so it will carry on when it next opens
+    Paused,
+    /// There isn't room for the download within the storage quotas
+    NoRoom,
+}
+
+/// Room set aside within the storage quotas for a download in progress
+#[derive(Debug, Clone, Copy)]
+struct Reservation {
+    podcast_id: i32,
+    bytes: u64,
+}
+
+/// Downloads queued episodes to disk, a few at a time.
+///
+/// The queue lives in the database, so downloads carry on where they left off after a restart.
+/// Partial downloads are kept next to their destination as `.part` files and resumed with a
+/// `Range` request.
+///
+/// Automatic downloads only run inside the download window, and are paused when it closes.
+/// Downloads that the user asked for straight away run whenever they are queued.
+#[derive(Debug)]
+pub(crate) struct DownloadManager {
+    client: HttpClient,
+    dir: PathBuf,
+    template: naming::NameTemplate,
+    limits: DownloadLimits,
+    /// Shared by every download, so that together they stay under the global limit
+    rate: Option<RateLimiter>,
+    slots: Arc<Semaphore>,
+    /// The room set aside for each download in progress, by download ID
+    reserved: Mutex<HashMap<i32, Reservation>>,
+    wake: Notify,
+    events: broadcast::Sender<DownloadEvent>,
+}
+
+impl DownloadManager {
+    /// Create a manager that downloads with `client` and saves episodes under `dir`, named
+    /// according to `template`, keeping to `limits`.
+    pub(crate) fn new(
+        client: HttpClient,
+        dir: impl Into<PathBuf>,
+        template: naming::NameTemplate,
+        limits: DownloadLimits,
+    ) -> Arc<Self> {
+        let (events, _) = broadcast::channel(100);
+        Arc::new(Self {
+            client,
+            dir: dir.into(),
+            template,
+            rate: limits.max_rate.map(RateLimiter::new),
+            slots: Arc::new(Semaphore::new(limits.concurrency.max(1))),
+            reserved: Mutex::new(HashMap::new()),
+            limits,
+            wake: Notify::new(),
+            events,
+        })
+    }
+
+    /// Listen for download events.
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<DownloadEvent> {
+        self.events.subscribe()
+    }
+
+    /// Queue `episode` to be downloaded from `enclosure`.
+    ///
+    /// Manual downloads start as soon as there is a free slot, whether or not the download window
+    /// is open. Otherwise they wait for the window. If another episode would be saved to the same
+    /// path, a number is added to the name.
+    pub(crate) async fn enqueue(
+        &self,
+        podcast: &Podcast,
+        episode: Episode,
+        enclosure: &Enclosure,
+        manual: bool,
+    ) -> Result<Download, db::Error> {
+        let enclosure = enclosure.clone();
+        let extension = media::extension_from_uri(enclosure.uri()).or_else(|| {
+            enclosure
+                .mime_type()
+                .and_then(media::extension_for_mime_type)
+                .map(str::to_owned)
+        });
+        let path = self.dir.join(
+            self.template
+                .render(podcast, &episode, extension.as_deref()),
+        );
+        let download = db::run(move |con| {
+            let path = unique_path(con, &episode, &path)?;
+            Download::enqueue(con, &episode, &enclosure, &path.to_string_lossy(), manual)
+        })
+        .await?;
+        self.wake.notify_one();
+        Ok(download)
+    }
+
+    /// Work through the queue until an error occurs talking to the database.
+    pub(crate) async fn run(self: Arc<Self>) -> Result<(), db::Error> {
+        let interrupted = db::run(Download::reset_interrupted).await?;
+        if interrupted > 0 {
+            tracing::info!("Resuming {} interrupted downloads", interrupted);
+        }
+        // The quotas may have changed since they were refused
+        let _ = db::run(|con| Download::retry_refused(con, None)).await?;
+        loop {
+            let permit = Arc::clone(&self.slots).acquire_owned().await?;
+            let now = chrono::Utc::now().naive_utc();
+            let time = chrono::Local::now().time();
+            let automatic = self.limits.allows(time);
+            if let Some(download) =
+                db::run(move |con| Download::claim_next(con, now, automatic)).await?
+            {
+                let manager = Arc::clone(&self);
+                let _ = tokio::task::spawn(async move {
+                    manager.download(download).await;
+                    drop(permit);
+                });
+                continue;
+            }
+            drop(permit);
+
+            // Nothing is ready, so sleep until something is queued, a retry is due, or the
+            // download window opens
+            let retry = db::run(move |con| Download::next_retry(con, automatic))
+                .await?
+                .map(|next| (next - now).to_std().unwrap_or_default());
+            let opens = Some(self.limits.until_allowed(time)).filter(|wait| !wait.is_zero());
+            match retry.into_iter().chain(opens).min() {
+                Some(wait) => {
+                    tokio::select! {
+                        () = self.wake.notified() => {}
+                        () = tokio::time::sleep(wait) => {}
+                    }
+                }
+                None => self.wake.notified().await,
+            }
+        }
+    }
+
+    async fn download(&self, download: Download) {
+        let id = download.id();
+        self.attempt(download).await;
+        // However it ended, the room set aside for it is either used up or no longer needed
+        let _ = self.reserved.lock().await.remove(&id);
+    }
+
+    async fn attempt(&self, mut download: Download) {
+        // Downloads whose size isn't known yet have room set aside as it becomes known
+        let needed = download
+            .total_bytes()
+            .or(download.expected_bytes())
+            .and_then(|bytes| u64::try_from(bytes).ok())
+            .unwrap_or(0);
+        match self.make_room(&download, needed).await {
+            Ok(true) => {}
+            Ok(false) => return self.refuse(&mut download).await,
+            Err(e) => tracing::error!("Failed to check the storage quota: {}", e),
+        }
+        self.emit(&download, DownloadStatus::Started);
+        let status = match self.fetch(&mut download).await {
+            Ok(Outcome::NoRoom) => return self.refuse(&mut download).await,
+            Ok(Outcome::Paused) => {
+                tracing::info!(
+                    "Pausing {} until the download window opens",
+                    redact_uri(download.uri())
+                );
+                download.pause();
+                let paused = download.clone();
+                if let Err(e) = db::run(move |con| paused.save(con)).await {
+                    tracing::error!("Failed to record paused download: {}", e);
+                }
+                DownloadStatus::Paused
+            }
+            Ok(Outcome::Completed { verified }) => {
+                let mut completed = download.clone();
+                match db::run(move |con| completed.complete(con, verified)).await {
+                    Ok(()) => DownloadStatus::Completed {
+                        path: download.path().into(),
+                    },
+                    Err(e) => {
+                        tracing::error!("Failed to record download: {}", e);
+                        return;
+                    }
+                }
+            }
+            Err(e) => {
+                tracing::warn!("Failed to download {}: {}", redact_uri(download.uri()), e);
+                download.record_failure(&e.to_string());
+                let failed = download.clone();
+                if let Err(e) = db::run(move |con| failed.save(con)).await {
+                    tracing::error!("Failed to record download failure: {}", e);
+                }
+                // Wake the queue so that it can sleep until this is due to be retried
+                self.wake.notify_one();
+                DownloadStatus::Failed {
+                    error: e.to_string(),
+                    will_retry: download.will_retry(),
+                }
+            }
+        };
+        self.emit(&download, status);
+    }
+
+    /// Hold `download` back until there is room for it, throwing away what it had downloaded
+    async fn refuse(&self, download: &mut Download) {
+        let error = "There isn't enough room for it within the storage quota";
+        tracing::warn!("Not downloading {}: {}", redact_uri(download.uri()), error);
+        if let Err(e) = discard(download, Path::new(&download.part_path())).await {
+            tracing::error!("Failed to delete refused download: {}", e);
+        }
+        download.refuse(error);
+        let refused = download.clone();
+        if let Err(e) = db::run(move |con| refused.save(con)).await {
+            tracing::error!("Failed to record refused download: {}", e);
+        }
+        let status = DownloadStatus::Failed {
+            error: error.into(),
+            will_retry: false,
+        };
+        self.emit(download, status);
+    }
+
+    /// Make room for `needed` bytes of `download` within the storage quotas, deleting played
+    /// downloads if need be, and set it aside for the download.
+    ///
+    /// The room set aside for other downloads in progress counts as used, so that downloads
+    /// running at the same time can't overshoot the quotas between them. Returns `false`, without
+    /// deleting anything or changing what is set aside, if there can't be enough room.
+    async fn make_room(&self, download: &Download, needed: u64) -> Result<bool, db::Error> {
+        // Held until the room is set aside, so that no other download can take it first
+        let mut reserved = self.reserved.lock().await;
+        let podcast_id = download.podcast_id();
+        let podcast_quota = db::run(move |con| Podcast::find(con, podcast_id))
+            .await?
+            .and_then(|podcast| podcast.max_download_bytes())
+            .and_then(|bytes| u64::try_from(bytes).ok());
+
+        for (quota, scope) in [
+            (podcast_quota, Some(podcast_id)),
+            (self.limits.max_storage, None),
+        ] {
+            let Some(quota) = quota else {
+                continue;
+            };
+            let elsewhere: u64 = reserved
+                .iter()
+                .filter(|(id, reservation)| {
+                    **id != download.id()
+                        && scope.is_none_or(|scope| reservation.podcast_id == scope)
+                })
+                .map(|(_, reservation)| reservation.bytes)
+                .sum();
+            // The episode's old copy isn't deleted to make room for its new one
+            let title = download.episode_title().to_owned();
+            let (used, evictable) = db::run(move |con| {
+                let used = storage::used(con, scope)?;
+                let evictable: Vec<(Episode, u64)> =
+                    storage::evictable(con, scope, Some((podcast_id, &title)))?
+                        .into_iter()
+                        .map(|episode| {
+                            let size = storage::downloaded_size(&episode);
+                            (episode, size)
+                        })
+                        .collect();
+                diesel::QueryResult::Ok((used, evictable))
+            })
+            .await?;
+            let mut used = used + elsewhere;
+            let freeable: u64 = evictable.iter().map(|(_, size)| size).sum();
+            if used.saturating_sub(freeable) + needed > quota {
+                return Ok(false);
+            }
+            for (episode, size) in &evictable {
+                if used + needed <= quota {
+                    break;
+                }
+                tracing::info!("Making room within the storage quota");
+                delete(episode).await?;
+                used = used.saturating_sub(*size);
+            }
+        }
+        let _ = reserved.insert(
+            download.id(),
+            Reservation {
+                podcast_id,
+                bytes: needed,
+            },
+        );
+        Ok(true)
+    }
+
+    /// How much room is set aside for `download`
+    async fn reserved_for(&self, download: &Download) -> u64 {
+        self.reserved
+            .lock()
+            .await
+            .get(&download.id())
+            .map_or(0, |reservation| reservation.bytes)
+    }
+
+    /// Download the rest of `download` into its `.part` file, check it, then move it into place.
+    ///
+    /// Downloads that end early are resumed straight away. Ones that turn out to be corrupt have
+    /// their `.part` file thrown away, so that the retry starts again from scratch.
+    async fn fetch(&self, download: &mut Download) -> Result<Outcome, db::Error> {
+        let part = PathBuf::from(download.part_path());
+        if let Some(parent) = part.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        let podcast_id = download.podcast_id();
+        let credentials = db::run(move |con| Credentials::for_podcast(con, podcast_id)).await?;
+        let mut resumes = 0;
+        // Checked against the length if there is one, and then against the integrity hash
+        let mut verified = loop {
+            let before = part_length(&part).await;
+            let (downloaded, total) =
+                match self.transfer(download, &part, credentials.as_ref()).await? {
+                    Transfer::Ended(downloaded, total) => (downloaded, total),
+                    Transfer::Paused => return Ok(Outcome::Paused),
+                    Transfer::NoRoom => return Ok(Outcome::NoRoom),
+                };
+            let feed_length = download
+                .expected_bytes()
+                .and_then(|length| u64::try_from(length).ok());
+            if let (Some(total), Some(feed_length)) = (total, feed_length) {
+                if total != feed_length {
+                    // Feeds are often out by a little, so the server gets the final say
+                    tracing::debug!(
+                        "{} is {} bytes, but the feed says {}",
+                        redact_uri(download.uri()),
+                        total,
+                        feed_length
+                    );
+                }
+            }
+            // Chunked responses don't say how long they are, so fall back to the feed
+            match total.or(feed_length) {
+                Some(expected) if downloaded < expected => {
+                    if downloaded > before && resumes < MAX_RESUMES {
+                        resumes += 1;
+                        tracing::info!(
+                            "{} ended after {} of {} bytes, resuming",
+                            redact_uri(download.uri()),
+                            downloaded,
+                            expected
+                        );
+                        continue;
+                    }
+                    return Err(format!(
+                        "Download ended after {} of {} bytes",
+                        downloaded, expected
+                    )
+                    .into());
+                }
+                Some(expected) if downloaded > expected && total.is_some() => {
+                    discard(download, &part).await?;
+                    return Err(format!(
+                        "Downloaded {} bytes, but expected {}",
+                        downloaded, expected
+                    )
+                    .into());
+                }
+                expected => break expected == Some(downloaded),
+            }
+        };
+
+        if let Some(integrity) = download.integrity().and_then(Integrity::parse) {
+            let path = part.clone();
+            let matches =
+                tokio::task::spawn_blocking(move || integrity.matches_file(&path)).await??;
+            if !matches {
+                discard(download, &part).await?;
+                return Err("Download doesn't match its integrity hash".into());
+            }
+            verified = true;
+        }
+        tokio::fs::rename(&part, download.path()).await?;
+        Ok(Outcome::Completed { verified })
+    }
+
+    /// Make one request for the rest of the `.part` file, keeping to the rate limits and the
+    /// storage quotas.
+    ///
+    /// More room is set aside once the server says how big the download is, and again whenever it
+    /// grows past what was set aside. Automatic downloads stop part way through if the download
+    /// window closes.
+    async fn transfer(
+        &self,
+        download: &mut Download,
+        part: &Path,
+        credentials: Option<&Credentials>,
+    ) -> Result<Transfer, db::Error> {
+        let existing = part_length(part).await;
+        let mut request = Request::get(download.uri(), credentials);
+        if existing > 0 {
+            request = request.header(reqwest::header::RANGE, format!("bytes={}-", existing));
+        }
+        let response = self.client.send(request).await?;
+        if response.status() == reqwest::StatusCode::RANGE_NOT_SATISFIABLE && existing > 0 {
+            // The part file already holds everything that there is
+            return Ok(Transfer::Ended(existing, Some(existing)));
+        }
+        if http::requires_auth(response.status()) {
+            return Err(format!("Authentication required ({})", response.status()).into());
+        }
+        let mut response = response.error_for_status()?;
+
+        let (mut file, mut downloaded, total) =
+            if response.status() == reqwest::StatusCode::PARTIAL_CONTENT {
+                let file = tokio::fs::OpenOptions::new()
+                    .append(true)
+                    .open(part)
+                    .await?;
+                let total = content_range_total(&response)
+                    .or_else(|| response.content_length().map(|length| length + existing));
+                (file, existing, total)
+            } else {
+                // The server ignored the range, so start again from the beginning
+                let file = tokio::fs::File::create(part).await?;
+                (file, 0, response.content_length())
+            };
+
+        let mut allowed = self.reserved_for(download).await;
+        if let Some(total) = total.filter(|total| *total > allowed) {
+            if !self.make_room(download, total).await? {
+                return Ok(Transfer::NoRoom);
+            }
+            allowed = total;
+        }
+
+        let own_rate = self.limits.max_rate_per_download.map(RateLimiter::new);
+        let mut paused = false;
+        let mut reported = downloaded;
+        let mut reports = 0_u32;
+        while let Some(chunk) = response.chunk().await? {
+            for rate in own_rate.iter().chain(&self.rate) {
+                rate.consume(chunk.len()).await;
+            }
+            file.write_all(&chunk).await?;
+            downloaded += chunk.len() as u64;
+            if downloaded > allowed {
+                // The server didn't say how big it is, or got it wrong
+                allowed = downloaded + RESERVE_STEP;
+                if !self.make_room(download, allowed).await? {
+                    return Ok(Transfer::NoRoom);
+                }
+            }
+            if downloaded - reported >= PROGRESS_INTERVAL {
+                reported = downloaded;
+                reports = reports.wrapping_add(1);
+                self.emit(download, DownloadStatus::Progress { downloaded, total });
+                if reports % SAVE_EVERY == 0 {
+                    self.save_progress(download, downloaded, total).await?;
+                }
+            }
+            if !download.is_manual() && !self.limits.allows(chrono::Local::now().time()) {
+                paused = true;
+                break;
+            }
+        }
+        file.flush().await?;
+        drop(file);
+        self.save_progress(download, downloaded, total).await?;
+        Ok(if paused {
+            Transfer::Paused
+        } else {
+            Transfer::Ended(downloaded, total)
+        })
+    }
+
+    async fn save_progress(
+        &self,
+        download: &mut Download,
+        downloaded: u64,
+        total: Option<u64>,
+    ) -> Result<(), db::Error> {
+        download.record_progress(
+            i64::try_from(downloaded)?,
+            total.map(i64::try_from).transpose()?,
+        );
+        let snapshot = download.clone();
+        db::run(move |con| snapshot.save(con)).await
+    }
+
+    fn emit(&self, download: &Download, status: DownloadStatus) {
+        // Nobody listening isn't an error
+        let _ = self.events.send(DownloadEvent {
+            podcast_id: download.podcast_id(),
+            episode_title: download.episode_title().to_owned(),
+            status,
+        });
+    }
+}
+
+/// Delete the downloaded copy of `episode`, and forget that it was downloaded
+pub(crate) async fn delete(episode: &Episode) -> Result<(), db::Error> {
+    let Some(path) = episode.local_uri() else {
+        return Ok(());
+    };
+    tracing::info!("Deleting the download of {}", episode.title());
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    let episode = episode.clone();
+    db::run(move |con| Download::forget(con, &episode)).await
+}
+
+/// The length of the `.part` file, or 0 if there isn't one yet
+async fn part_length(part: &Path) -> u64 {
+    tokio::fs::metadata(part)
+        .await
+        .map_or(0, |metadata| metadata.len())
+}
+
+/// Throw away a corrupt `.part` file, so that the download starts again from scratch
+async fn discard(download: &mut Download, part: &Path) -> Result<(), db::Error> {
+    download.discard_progress();
+    match tokio::fs::remove_file(part).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Get the full length from a `Content-Range: bytes <start>-<end>/<length>` header
+fn content_range_total(response: &reqwest::Response) -> Option<u64> {
+    let range = response
+        .headers()
+        .get(reqwest::header::CONTENT_RANGE)?
+        .to_str()
+        .ok()?;
+    range.rsplit_once('/')?.1.trim().parse().ok()
+}
+
+/// Find a path for `episode` that no other download is using, starting with `path`
+fn unique_path(
+    con: &mut diesel::SqliteConnection,
+    episode: &Episode,
+    path: &Path,
+) -> diesel::QueryResult<PathBuf> {
+    let mut candidate = path.to_owned();
+    let mut number = 1;
+    while candidate.exists() || Download::path_in_use(con, episode, &candidate.to_string_lossy())? {
+        number += 1;
+        candidate = naming::with_suffix(path, number);
+    }
+    Ok(candidate)
+}
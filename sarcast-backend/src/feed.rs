@@ -0,0 +1,91 @@
+use sarcast_data::models::{redact_uri, Credentials, Source};
+
+use crate::db;
+use crate::http::{self, HttpClient, Request};
+
+/// An error that occurred while fetching or parsing a feed.
+#[derive(Debug)]
+pub(crate) struct FetchError {
+    status: Option<u16>,
+    message: String,
+}
+
+impl std::fmt::Display for FetchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for FetchError {}
+
+/// Fetch and parse the feed for `source`, recording the outcome against it in the database.
+///
+/// Returns `Ok(None)` without making a request if the source is still backing off after
+/// previous failures. A feed that refuses its credentials is recorded as requiring
+/// authentication, which [`Source::requires_auth`] reports.
+pub(crate) async fn refresh(
+    client: &HttpClient,
+    source: &mut Source,
+) -> Result<Option<rss::Channel>, db::Error> {
+    if !source.is_refresh_due(chrono::Utc::now().naive_utc()) {
+        tracing::debug!(
+            "Skipping {} until {:?} after {} failures",
+            redact_uri(source.uri()),
+            source.next_refresh(),
+            source.failure_count()
+        );
+        return Ok(None);
+    }
+
+    let id = source.id();
+    let credentials = db::run(move |con| Credentials::for_source(con, id)).await?;
+    let result = fetch(client, source.uri(), credentials.as_ref()).await;
+    match &result {
+        Ok((status, _)) => source.record_success(Some(*status)),
+        Err(e) => {
+            tracing::warn!("Failed to refresh {}: {}", redact_uri(source.uri()), e);
+            source.record_failure(&e.to_string(), e.status);
+        }
+    }
+    let snapshot = source.clone();
+    db::run(move |con| snapshot.save(con)).await?;
+
+    Ok(Some(result?.1))
+}
+
+async fn fetch(
+    client: &HttpClient,
+    uri: &str,
+    credentials: Option<&Credentials>,
+) -> Result<(u16, rss::Channel), FetchError> {
+    let response = client
+        .send(Request::get(uri, credentials))
+        .await
+        .map_err(|e| FetchError {
+            status: e
+                .downcast_ref::<reqwest::Error>()
+                .and_then(reqwest::Error::status)
+                .map(|s| s.as_u16()),
+            message: e.to_string(),
+        })?;
+    let status = response.status().as_u16();
+    if http::requires_auth(response.status()) {
+        return Err(FetchError {
+            status: Some(status),
+            message: format!("Authentication required ({})", status),
+        });
+    }
+    let response = response.error_for_status().map_err(|e| FetchError {
+        status: Some(status),
+        message: e.to_string(),
+    })?;
+    let feed = response.bytes().await.map_err(|e| FetchError {
+        status: Some(status),
+        message: e.to_string(),
+    })?;
+    let channel = rss::Channel::read_from(&feed[..]).map_err(|e| FetchError {
+        status: Some(status),
+        message: e.to_string(),
+    })?;
+    Ok((status, channel))
+}
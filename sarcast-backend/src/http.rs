@@ -0,0 +1,334 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+use bytes::Bytes;
+use reqwest::header::{HeaderName, LOCATION};
+use sarcast_data::models::Credentials;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+pub(crate) type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// How many redirects a request follows before it is given up on
+const MAX_REDIRECTS: usize = 10;
+
+/// Settings for every HTTP request that is made, whether for feeds, downloads or streams.
+#[derive(Debug, Clone)]
+pub(crate) struct HttpConfig {
+    /// A proxy to send every request through, such as `http://proxy:3128` or
+    /// `socks5://proxy:1080`. Without one, the usual proxy environment variables are used.
+    pub(crate) proxy: Option<String>,
+    /// What to send as the `User-Agent` header
+    pub(crate) user_agent: String,
+    /// PEM files of extra root certificates to trust, such as the one for a proxy that inspects
+    /// TLS
+    pub(crate) root_certificates: Vec<PathBuf>,
+    /// How long to wait to connect to a server
+    pub(crate) connect_timeout: Duration,
+    /// How long a response can go without sending anything before it is given up on
+    pub(crate) read_timeout: Duration,
+    /// How many requests can be open to one host at once
+    pub(crate) max_connections_per_host: usize,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            proxy: None,
+            user_agent: concat!("sarcast/", env!("CARGO_PKG_VERSION")).into(),
+            root_certificates: vec![],
+            connect_timeout: Duration::from_secs(15),
+            read_timeout: Duration::from_secs(20),
+            max_connections_per_host: 6,
+        }
+    }
+}
+
+/// The HTTP client that everything shares, so that they all follow the same [`HttpConfig`].
+///
+/// Cloning it is cheap, and the clones share their connections and per-host limits.
+#[derive(Debug, Clone)]
+pub(crate) struct HttpClient {
+    client: reqwest::Client,
+    read_timeout: Duration,
+    hosts: Arc<Hosts>,
+}
+
+/// Limits how many requests are open to each host
+#[derive(Debug)]
+struct Hosts {
+    limit: usize,
+    open: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl HttpClient {
+    /// Build a client from `config`, failing if the proxy or certificates can't be used.
+    pub(crate) fn new(config: &HttpConfig) -> Result<Self, Error> {
+        let mut builder = reqwest::Client::builder()
+            .user_agent(&config.user_agent)
+            .connect_timeout(config.connect_timeout)
+            // Redirects are followed by `send`, which checks the credentials at every hop
+            .redirect(reqwest::redirect::Policy::none())
+            .pool_max_idle_per_host(config.max_connections_per_host);
+        if let Some(proxy) = &config.proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy.as_str())?);
+        }
+        for path in &config.root_certificates {
+            let pem = std::fs::read(path)
+                .map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+            builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+        }
+        Ok(Self {
+            client: builder.build()?,
+            read_timeout: config.read_timeout,
+            hosts: Arc::new(Hosts {
+                limit: config.max_connections_per_host.max(1),
+                open: Mutex::new(HashMap::new()),
+            }),
+        })
+    }
+
+    /// Send `request`, following any redirects.
+    ///
+    /// Each hop is sent once there is room for another connection to its host, and only gets the
+    /// request's credentials if they apply to it. The connection counts against the limit until
+    /// the response is dropped. Errors leave out the URL, as it may hold a token.
+    pub(crate) async fn send(&self, request: Request) -> Result<Response, Error> {
+        let mut url = request.url.clone();
+        for _ in 0..=MAX_REDIRECTS {
+            let response = self.send_once(request.build(&self.client, &url)).await?;
+            let next = response
+                .status()
+                .is_redirection()
+                .then(|| response.headers().get(LOCATION))
+                .flatten()
+                .and_then(|location| location.to_str().ok())
+                .and_then(|location| response.url().join(location).ok());
+            match next {
+                Some(next) => url = next.into(),
+                None => return Ok(response),
+            }
+        }
+        Err(format!("More than {} redirects", MAX_REDIRECTS).into())
+    }
+
+    /// Send a single request, without following redirects
+    async fn send_once(&self, request: reqwest::RequestBuilder) -> reqwest::Result<Response> {
+        let request = request.build().map_err(reqwest::Error::without_url)?;
+        let host = format!(
+            "{}:{}",
+            request.url().host_str().unwrap_or_default(),
+            request.url().port_or_known_default().unwrap_or_default()
+        );
+        let slots = {
+            let mut open = self
+                .hosts
+                .open
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            Arc::clone(
+                open.entry(host)
+                    .or_insert_with(|| Arc::new(Semaphore::new(self.hosts.limit))),
+            )
+        };
+        let permit = slots
+            .acquire_owned()
+            .await
+            .expect("Host semaphores are never closed");
+        Ok(Response {
+            inner: self
+                .client
+                .execute(request)
+                .await
+                .map_err(reqwest::Error::without_url)?,
+            read_timeout: self.read_timeout,
+            permit,
+        })
+    }
+}
+
+/// A `GET` request that hasn't been sent yet.
+///
+/// It keeps hold of the credentials of the source that it belongs to, so that they can be
+/// checked against every hop that it is redirected through. They are left off requests to other
+/// hosts, such as the trackers and CDNs that episodes are often served through, so that they
+/// don't leak to them.
+#[derive(Debug)]
+pub(crate) struct Request {
+    url: String,
+    headers: Vec<(HeaderName, String)>,
+    credentials: Option<Credentials>,
+}
+
+impl Request {
+    /// Start building a `GET` request for `url`, using the `credentials` of the source that it
+    /// belongs to where they apply
+    pub(crate) fn get(url: impl AsRef<str>, credentials: Option<&Credentials>) -> Self {
+        Self {
+            url: url.as_ref().to_owned(),
+            headers: vec![],
+            credentials: credentials.cloned(),
+        }
+    }
+
+    /// Add a header, which is sent to every hop
+    pub(crate) fn header(mut self, name: HeaderName, value: String) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+
+    /// Build the request for the hop to `url`
+    fn build(&self, client: &reqwest::Client, url: &str) -> reqwest::RequestBuilder {
+        let mut request = client.get(url);
+        for (name, value) in &self.headers {
+            request = request.header(name.clone(), value.as_str());
+        }
+        let credentials = self
+            .credentials
+            .as_ref()
+            .filter(|credentials| credentials.applies_to(url));
+        if let Some(credentials) = credentials {
+            if let Some(username) = credentials.username() {
+                request = request.basic_auth(username, credentials.password());
+            }
+            let query: Vec<(&str, &str)> = credentials.query().collect();
+            if !query.is_empty() {
+                request = request.query(&query);
+            }
+            for (name, value) in credentials.headers() {
+                request = request.header(name, value);
+            }
+        }
+        request
+    }
+}
+
+/// A response that holds on to its host's connection slot, and times out reads that stall.
+#[derive(Debug)]
+pub(crate) struct Response {
+    inner: reqwest::Response,
+    read_timeout: Duration,
+    /// Held until the response is dropped
+    permit: OwnedSemaphorePermit,
+}
+
+impl std::ops::Deref for Response {
+    type Target = reqwest::Response;
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl Response {
+    /// Turn an error status into an error, like [`reqwest::Response::error_for_status`]
+    pub(crate) fn error_for_status(self) -> reqwest::Result<Self> {
+        let Self {
+            inner,
+            read_timeout,
+            permit,
+        } = self;
+        Ok(Self {
+            inner: inner
+                .error_for_status()
+                .map_err(reqwest::Error::without_url)?,
+            read_timeout,
+            permit,
+        })
+    }
+
+    /// Read the next chunk of the body, or `None` once it has all been read
+    pub(crate) async fn chunk(&mut self) -> Result<Option<Bytes>, Error> {
+        match tokio::time::timeout(self.read_timeout, self.inner.chunk()).await {
+            Ok(chunk) => Ok(chunk.map_err(reqwest::Error::without_url)?),
+            Err(_) => Err("Timed out waiting for data".into()),
+        }
+    }
+
+    /// Read the whole body
+    pub(crate) async fn bytes(mut self) -> Result<Vec<u8>, Error> {
+        let mut body = vec![];
+        while let Some(chunk) = self.chunk().await? {
+            body.extend_from_slice(&chunk);
+        }
+        Ok(body)
+    }
+}
+
+/// Whether `status` means that the request needs credentials, or that the ones sent were refused
+pub(crate) fn requires_auth(status: reqwest::StatusCode) -> bool {
+    status == reqwest::StatusCode::UNAUTHORIZED || status == reqwest::StatusCode::FORBIDDEN
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread::JoinHandle;
+
+    use diesel::{Connection, SqliteConnection};
+    use diesel_migrations::MigrationHarness;
+    use sarcast_data::models::Source;
+
+    use super::*;
+
+    /// Answer one request on `listener` with `response`, and return the head of the request
+    fn serve_once(listener: TcpListener, response: String) -> JoinHandle<String> {
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut head = vec![];
+            let mut buffer = [0; 1024];
+            while !head.ends_with(b"\r\n\r\n") {
+                let read = stream.read(&mut buffer).unwrap();
+                if read == 0 {
+                    break;
+                }
+                head.extend_from_slice(&buffer[..read]);
+            }
+            stream.write_all(response.as_bytes()).unwrap();
+            String::from_utf8(head).unwrap().to_ascii_lowercase()
+        })
+    }
+
+    #[tokio::test]
+    pub(crate) async fn redirects_leave_credentials_behind() -> Result<(), Error> {
+        let (feed_host, cdn) = (
+            TcpListener::bind("127.0.0.1:0")?,
+            TcpListener::bind("127.0.0.1:0")?,
+        );
+        let feed_uri = format!("http://{}/feed.xml", feed_host.local_addr()?);
+        let episode_uri = format!("http://{}/episode.mp3", feed_host.local_addr()?);
+        let cdn_uri = format!("http://{}/episode.mp3", cdn.local_addr()?);
+
+        let mut con = SqliteConnection::establish(":memory:")?;
+        let _ = con.run_pending_migrations(sarcast_data::MIGRATIONS)?;
+        let mut credentials = Credentials::new(&Source::get_or_create(&mut con, &feed_uri)?);
+        credentials.add_header("X-Api-Key", "secret-key")?;
+        credentials.add_query("token", "secret-token");
+
+        let redirected = serve_once(
+            feed_host,
+            format!(
+                "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                cdn_uri
+            ),
+        );
+        let served = serve_once(
+            cdn,
+            "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok".into(),
+        );
+        let client = HttpClient::new(&HttpConfig::default())?;
+        let request = Request::get(&episode_uri, Some(&credentials))
+            .header(reqwest::header::RANGE, "bytes=0-".into());
+        let body = client.send(request).await?.bytes().await?;
+        assert_eq!(body, b"ok");
+
+        let (redirected, served) = (redirected.join().unwrap(), served.join().unwrap());
+        assert!(redirected.contains("x-api-key: secret-key"));
+        assert!(redirected.contains("token=secret-token"));
+        // The CDN gets everything else, but none of the secrets
+        assert!(served.contains("range: bytes=0-"));
+        assert!(!served.contains("secret"));
+        Ok(())
+    }
+}
@@ -0,0 +1,83 @@
+use std::io::Read;
+use std::path::Path;
+
+use base64::Engine;
+use sha2::Digest;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Algorithm {
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+/// The hashes from a Subresource Integrity string, like those given by `<podcast:integrity>`.
+///
+/// <https://www.w3.org/TR/SRI/>
+#[derive(Debug, Clone)]
+pub(crate) struct Integrity {
+    algorithm: Algorithm,
+    digests: Vec<Vec<u8>>,
+}
+
+impl Integrity {
+    /// Parse the strongest hashes from an SRI string.
+    ///
+    /// Returns `None` if there are no hashes that we know how to check.
+    pub(crate) fn parse(sri: &str) -> Option<Self> {
+        let hashes: Vec<(Algorithm, Vec<u8>)> = sri
+            .split_whitespace()
+            .filter_map(|hash| {
+                // Anything after a '?' is an option, which we don't need
+                let hash = hash.split('?').next()?;
+                let (algorithm, digest) = hash.split_once('-')?;
+                let algorithm = match algorithm.to_ascii_lowercase().as_str() {
+                    "sha256" => Algorithm::Sha256,
+                    "sha384" => Algorithm::Sha384,
+                    "sha512" => Algorithm::Sha512,
+                    _ => return None,
+                };
+                let digest = base64::engine::general_purpose::STANDARD
+                    .decode(digest)
+                    .ok()?;
+                Some((algorithm, digest))
+            })
+            .collect();
+        // Only the strongest algorithm counts, as in browsers
+        let algorithm = hashes.iter().map(|(algorithm, _)| *algorithm).max()?;
+        Some(Self {
+            algorithm,
+            digests: hashes
+                .into_iter()
+                .filter(|(a, _)| *a == algorithm)
+                .map(|(_, digest)| digest)
+                .collect(),
+        })
+    }
+
+    /// Check whether the file at `path` matches any of the hashes.
+    ///
+    /// This reads the whole file, so should be run on the blocking thread pool.
+    pub(crate) fn matches_file(&self, path: &Path) -> std::io::Result<bool> {
+        let file = std::fs::File::open(path)?;
+        let digest = match self.algorithm {
+            Algorithm::Sha256 => hash::<sha2::Sha256>(file)?,
+            Algorithm::Sha384 => hash::<sha2::Sha384>(file)?,
+            Algorithm::Sha512 => hash::<sha2::Sha512>(file)?,
+        };
+        Ok(self.digests.contains(&digest))
+    }
+}
+
+fn hash<D: Digest>(mut file: std::fs::File) -> std::io::Result<Vec<u8>> {
+    let mut hasher = D::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher.finalize().to_vec())
+}
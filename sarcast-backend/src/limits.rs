@@ -0,0 +1,155 @@
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use chrono::NaiveTime;
+
+/// Limits on how much of the connection and the disk downloads may use, and when.
+#[derive(Debug, Clone)]
+pub(crate) struct DownloadLimits {
+    /// How many episodes are downloaded at once
+    pub(crate) concurrency: usize,
+    /// The most bytes per second that all downloads may use between them
+    pub(crate) max_rate: Option<u64>,
+    /// The most bytes per second that any one download may use
+    pub(crate) max_rate_per_download: Option<u64>,
+    /// When automatic downloads may run. Manual downloads run whenever they are asked for.
+    pub(crate) window: Option<Window>,
+    /// The most bytes that all downloaded episodes may take up between them. Each podcast can
+    /// have its own limit too.
+    pub(crate) max_storage: Option<u64>,
+}
+
+impl Default for DownloadLimits {
+    fn default() -> Self {
+        Self {
+            concurrency: 2,
+            max_rate: None,
+            max_rate_per_download: None,
+            window: None,
+            max_storage: None,
+        }
+    }
+}
+
+impl DownloadLimits {
+    /// Whether automatic downloads may run at the local time `now`
+    pub(crate) fn allows(&self, now: NaiveTime) -> bool {
+        match self.window {
+            Some(window) => window.contains(now),
+            None => true,
+        }
+    }
+
+    /// How long until automatic downloads may run after the local time `now`
+    pub(crate) fn until_allowed(&self, now: NaiveTime) -> Duration {
+        self.window
+            .map_or(Duration::ZERO, |window| window.until_open(now))
+    }
+}
+
+/// A time of day that downloads may run in, such as `01:00-06:00`.
+///
+/// Windows that end before they start run overnight, and ones that start and end at the same
+/// time are always open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Window {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl Window {
+    pub(crate) fn new(start: NaiveTime, end: NaiveTime) -> Self {
+        Self { start, end }
+    }
+
+    /// Whether the window is open at `time`
+    pub(crate) fn contains(&self, time: NaiveTime) -> bool {
+        match self.start.cmp(&self.end) {
+            std::cmp::Ordering::Equal => true,
+            std::cmp::Ordering::Less => self.start <= time && time < self.end,
+            std::cmp::Ordering::Greater => self.start <= time || time < self.end,
+        }
+    }
+
+    /// How long until the window next opens after `time`, or nothing if it is already open
+    pub(crate) fn until_open(&self, time: NaiveTime) -> Duration {
+        if self.contains(time) {
+            return Duration::ZERO;
+        }
+        let wait = self.start.signed_duration_since(time);
+        let wait = if wait < chrono::Duration::zero() {
+            wait + chrono::Duration::days(1)
+        } else {
+            wait
+        };
+        wait.to_std().unwrap_or_default()
+    }
+}
+
+impl std::str::FromStr for Window {
+    type Err = String;
+    fn from_str(window: &str) -> Result<Self, Self::Err> {
+        let (start, end) = window
+            .split_once('-')
+            .ok_or_else(|| format!("Expected a window like 01:00-06:00, got {}", window))?;
+        let time = |time: &str| {
+            NaiveTime::parse_from_str(time.trim(), "%H:%M")
+                .map_err(|e| format!("Invalid time {}: {}", time.trim(), e))
+        };
+        Ok(Self::new(time(start)?, time(end)?))
+    }
+}
+
+/// A token bucket that limits how fast bytes pass through it.
+///
+/// Up to a second's worth of bytes can go through in a burst, after which callers are made to
+/// wait. Slowing down reading from a response lets TCP slow the sender down for us.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    bytes_per_second: f64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    available: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(bytes_per_second: u64) -> Self {
+        #[allow(clippy::cast_precision_loss)]
+        let bytes_per_second = bytes_per_second.max(1) as f64;
+        Self {
+            bytes_per_second,
+            bucket: Mutex::new(Bucket {
+                available: bytes_per_second,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take `bytes` from the bucket, waiting for as long as it takes to refill
+    pub(crate) async fn consume(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap_or_else(PoisonError::into_inner);
+            let now = Instant::now();
+            let refilled = now.duration_since(bucket.updated).as_secs_f64() * self.bytes_per_second;
+            bucket.available = (bucket.available + refilled).min(self.bytes_per_second);
+            bucket.updated = now;
+            // Going into debt means that everyone sharing the bucket waits their turn
+            #[allow(clippy::cast_precision_loss)]
+            {
+                bucket.available -= bytes as f64;
+            }
+            if bucket.available < 0.0 {
+                Duration::from_secs_f64(-bucket.available / self.bytes_per_second)
+            } else {
+                Duration::ZERO
+            }
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
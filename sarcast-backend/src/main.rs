@@ -7,71 +7,280 @@
 #![deny(unused)]
 #![deny(clippy::pedantic)]
 
+use sarcast_data::media;
+use sarcast_data::models::{
+    redact_uri, Codec, Credentials, Enclosure, Episode, NewEpisode, SelectionPolicy, Source,
+};
+use sarcast_data::naming::NameTemplate;
+use std::time::Duration;
 use symphonia::core::io::MediaSource;
-use symphonia::core::meta::{MetadataRevision, TableOfContentsItem};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::sync::{broadcast, mpsc};
+use symphonia::core::meta::MetadataRevision;
+use symphonia::core::probe::Hint;
+use tokio::sync::mpsc;
 
 mod audio_thread;
+mod autodownload;
+mod cache;
+mod chapters;
+mod db;
 mod decoder;
+mod download;
+mod feed;
+mod http;
+mod integrity;
+mod limits;
+mod mp4;
+mod silence;
+mod stretch;
+
+pub use chapters::{Chapter, Chapters};
+pub use mp4::ChapterScan;
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     tracing_subscriber::fmt()
         .with_max_level(tracing_subscriber::filter::LevelFilter::DEBUG)
         .init();
 
+    let client = http::HttpClient::new(&http::HttpConfig::default())?;
     let (send, recv) = mpsc::channel(1);
-    let (metadata_send, mut metadata_recv) = mpsc::channel(1000);
+    let (metadata_send, metadata_recv) = mpsc::channel(1000);
+    let events = metadata_send.clone();
     let _audio_task = tokio::task::spawn_blocking(move || audio_thread::run(metadata_send, recv));
 
-    let feed = reqwest::get("https://biblethinker.castos.com/feed")
-        .await?
-        .bytes()
-        .await?;
-    // let feed = reqwest::get("https://atp.fm/rss").await?.bytes().await?;
-    // let feed = reqwest::get("https://tilos.hu/feed/show/hi-fi-budapest")
-    //     .await?
-    //     .bytes()
-    //     .await?;
-    let mut atom = rss::Channel::read_from(&feed[..])?;
+    let limits = limits::DownloadLimits {
+        max_rate: Some(2 * 1024 * 1024),
+        window: Some("01:00-06:00".parse()?),
+        ..limits::DownloadLimits::default()
+    };
+    let downloads = download::DownloadManager::new(
+        client.clone(),
+        "downloads",
+        NameTemplate::default(),
+        limits,
+    );
+    let _ = tokio::task::spawn(log_downloads(downloads.subscribe()));
+    let _download_task = tokio::task::spawn(std::sync::Arc::clone(&downloads).run());
+
+    let mut source =
+        db::run(|con| Source::get_or_create(con, "https://biblethinker.castos.com/feed")).await?;
+    // let mut source = db::run(|con| Source::get_or_create(con, "https://atp.fm/rss")).await?;
+    // let mut source = db::run(|con| {
+    //     Source::get_or_create(con, "https://tilos.hu/feed/show/hi-fi-budapest")
+    // })
+    // .await?;
+    let mut atom = match feed::refresh(&client, &mut source).await? {
+        Some(channel) => channel,
+        None => return Ok(()),
+    };
+    let indexed = {
+        let channel = atom.clone();
+        db::run(move |con| sarcast_data::feed::index(con, &source, &channel)).await?
+    };
+    autodownload::apply(&downloads, &indexed.podcast).await?;
+    log_storage_usage().await?;
+    for episode in &indexed.removed {
+        tracing::info!(
+            "{} was removed from {}",
+            episode.title(),
+            indexed.podcast.title()
+        );
+    }
     atom.set_items(atom.items[2..3].to_owned());
     println!("{:?}", atom);
     println!("{:#?}", atom.items[0].enclosure());
-    let sub_send = send.clone();
-    let _ = tokio::task::spawn(async move {
-        let send = sub_send;
-        while let Some(metadata) = metadata_recv.recv().await {
-            match metadata {
-                ReceivedData::NewMetadata(metadata) => {
-                    if let Some(table_of_contents) = &metadata.table_of_contents() {
-                        if let TableOfContentsItem::Chapter(chapter) = &table_of_contents.items[2] {
-                            send.send(PlaybackInstructions::Seek(u64::from(chapter.start_ms)))
-                                .await
-                                .unwrap();
-                        }
+    let episode = NewEpisode::try_from((&atom.items[0], &indexed.podcast))?;
+    let chosen = {
+        let (title, podcast_id) = (episode.title().to_owned(), indexed.podcast.id());
+        db::run(move |con| match Episode::find(con, podcast_id, &title)? {
+            Some(episode) => Enclosure::choose(
+                con,
+                &episode,
+                &SelectionPolicy::PreferCodecs(vec![Codec::Opus, Codec::Aac]),
+            )
+            .map(|enclosure| enclosure.map(|enclosure| (episode, enclosure))),
+            None => Ok(None),
+        })
+        .await?
+    };
+    if let Some((episode, enclosure)) = chosen {
+        // Play the downloaded copy if there is one, otherwise stream it while it downloads
+        let stream = match episode.local_uri() {
+            Some(path) => Stream::File(path.into()),
+            None => Stream::Url(reqwest::Url::try_from(enclosure.uri())?),
+        };
+        let _ = tokio::task::spawn(handle_playback_events(metadata_recv, episode.clone()));
+        let _ = downloads
+            .enqueue(&indexed.podcast, episode.clone(), &enclosure, true)
+            .await?;
+        let extension = media::extension_from_uri(enclosure.uri());
+        let hint = decoder::SymphoniaDecoder::hint(enclosure.mime_type(), extension.as_deref());
+        let mp4 = media::is_mp4(enclosure.mime_type(), extension.as_deref());
+        stream_podcast(&client, send.clone(), events, &episode, stream, hint, mp4).await?;
+    }
+    _audio_task.await?;
+    Ok(())
+}
+
+/// How often the play position is saved while an episode plays
+const SAVE_POSITION_EVERY: Duration = Duration::from_secs(15);
+/// How close to the end playback has to get for an episode to count as played, so that outros
+/// and credits don't have to be sat through
+const PLAYED_WITHIN: Duration = Duration::from_secs(30);
+
+/// React to what the audio thread reports while `episode` plays
+async fn handle_playback_events(mut events: mpsc::Receiver<ReceivedData>, episode: Episode) {
+    let mut saved: Option<Duration> = None;
+    // Silence trimming saves time a gap at a time, which is saved along with the position
+    let mut time_saved = Duration::ZERO;
+    let mut length = episode
+        .duration()
+        .and_then(|millis| u64::try_from(millis).ok())
+        .map(Duration::from_millis);
+    let near_end = |position: Duration, length: Option<Duration>| matches!(length, Some(length) if position + PLAYED_WITHIN >= length);
+    let mut played = false;
+    while let Some(event) = events.recv().await {
+        match event {
+            ReceivedData::NewMetadata(metadata) => {
+                tracing::debug!("{} has {} tags", episode.title(), metadata.tags().len());
+            }
+            ReceivedData::Chapters(chapters) => {
+                tracing::info!("{} has {} chapters", episode.title(), chapters.len());
+            }
+            ReceivedData::ChapterChanged(index, chapter) => {
+                let title = chapter.title.as_deref().unwrap_or("Untitled");
+                tracing::info!("Chapter {}: {} ({:?})", index + 1, title, chapter.start);
+            }
+            ReceivedData::Duration(duration) => {
+                tracing::info!("{} is {:?} long", episode.title(), duration);
+                length = Some(duration);
+                // The media knows better than the feed, which is often rounded or out of date
+                let milliseconds = i32::try_from(duration.as_millis()).ok();
+                if milliseconds.is_some() && milliseconds != episode.duration() {
+                    let (podcast_id, title) = (episode.podcast_id(), episode.title().to_owned());
+                    let saved = db::run(move |con| {
+                        Episode::set_duration(con, podcast_id, &title, milliseconds)
+                    })
+                    .await;
+                    if let Err(e) = saved {
+                        tracing::warn!("Failed to save the duration: {}", e);
                     }
                 }
-                ReceivedData::NewTimestamp(ts) => {
-                    let _ = ts;
-                    // println!("Timestamp: {}", ts);
+            }
+            ReceivedData::Buffering(buffering) => {
+                tracing::info!("Buffering: {}", buffering);
+            }
+            ReceivedData::NewTimestamp(position) => {
+                let due = match saved {
+                    Some(saved) => position.abs_diff(saved) >= SAVE_POSITION_EVERY,
+                    None => true,
+                };
+                if !played && near_end(position, length) {
+                    mark_played(&episode).await;
+                    played = true;
+                }
+                if due {
+                    let resume_at = resume_at(position, played && near_end(position, length));
+                    save_progress(&episode, resume_at, std::mem::take(&mut time_saved)).await;
+                    saved = Some(position);
+                }
+            }
+            ReceivedData::Seeked(Ok(position)) => tracing::info!("Seeked to {:?}", position),
+            ReceivedData::Seeked(Err(e)) => tracing::warn!("Failed to seek: {}", e),
+            ReceivedData::Paused(position) | ReceivedData::Stopped(position) => {
+                if !played && near_end(position, length) {
+                    mark_played(&episode).await;
+                    played = true;
                 }
+                let resume_at = resume_at(position, played && near_end(position, length));
+                save_progress(&episode, resume_at, std::mem::take(&mut time_saved)).await;
+                saved = Some(position);
             }
+            ReceivedData::TimeSaved(saved) => time_saved += saved,
         }
-    });
-    if let Some(enclosure) = atom.items[0].enclosure() {
-        let _ = enclosure;
-        stream_podcast(
-            send.clone(),
-            Stream::File("test.mp3".into()),
-            // Stream::Url(reqwest::Url::try_from(enclosure.url())?),
-        )
-        .await?;
     }
-    _audio_task.await?;
+}
+
+/// Where to resume from next time after getting to `position`, which is the beginning once the
+/// end of an episode has been played
+fn resume_at(position: Duration, finished: bool) -> Duration {
+    if finished {
+        Duration::ZERO
+    } else {
+        position
+    }
+}
+
+/// Remember that `episode` has been played, so that it no longer counts as unplayed and its
+/// download can be deleted by the podcast's rules
+async fn mark_played(episode: &Episode) {
+    let (podcast_id, title) = (episode.podcast_id(), episode.title().to_owned());
+    let now = chrono::Utc::now().timestamp();
+    let marked = db::run(move |con| Episode::set_played(con, podcast_id, &title, Some(now))).await;
+    if let Err(e) = marked {
+        tracing::warn!("Failed to mark the episode as played: {}", e);
+    }
+}
+
+/// Remember that playback of `episode` got to `position`, to resume from there next time, and
+/// add the time that silence trimming has saved since last time
+async fn save_progress(episode: &Episode, position: Duration, time_saved: Duration) {
+    let (podcast_id, title) = (episode.podcast_id(), episode.title().to_owned());
+    let position = i32::try_from(position.as_millis()).unwrap_or(i32::MAX);
+    let time_saved = i64::try_from(time_saved.as_millis()).unwrap_or(i64::MAX);
+    let saved = db::run(move |con| {
+        Episode::set_play_position(con, podcast_id, &title, position).and_then(|()| {
+            if time_saved > 0 {
+                Episode::add_time_saved(con, podcast_id, &title, time_saved)
+            } else {
+                Ok(())
+            }
+        })
+    })
+    .await;
+    if let Err(e) = saved {
+        tracing::warn!("Failed to save the play position: {}", e);
+    }
+}
+
+async fn log_storage_usage() -> Result<(), db::Error> {
+    for usage in db::run(sarcast_data::storage::usage_by_podcast).await? {
+        tracing::info!(
+            "Podcast {} has {} downloads taking up {} bytes",
+            usage.podcast_id,
+            usage.episodes,
+            usage.bytes
+        );
+    }
     Ok(())
 }
 
+async fn log_downloads(mut events: tokio::sync::broadcast::Receiver<download::DownloadEvent>) {
+    while let Ok(event) = events.recv().await {
+        let title = format!("{} ({})", event.episode_title, event.podcast_id);
+        match event.status {
+            download::DownloadStatus::Started => tracing::info!("Downloading {}", title),
+            download::DownloadStatus::Progress { downloaded, total } => {
+                tracing::debug!("{}: {} / {:?} bytes", title, downloaded, total);
+            }
+            download::DownloadStatus::Completed { path } => {
+                tracing::info!("Downloaded {} to {}", title, path.display());
+            }
+            download::DownloadStatus::Failed { error, will_retry } => {
+                tracing::warn!(
+                    "Failed to download {} (retrying: {}): {}",
+                    title,
+                    will_retry,
+                    error
+                );
+            }
+            download::DownloadStatus::Paused => {
+                tracing::info!("Paused {} until the download window opens", title);
+            }
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 #[allow(unused)]
 pub enum Stream {
@@ -79,160 +288,184 @@ pub enum Stream {
     Url(reqwest::Url),
 }
 
+/// What is known about a stream before it is opened
 #[derive(Debug)]
-pub struct Downloader {
-    full_size: u64,
-    streamed_bytes: std::sync::Arc<tokio::sync::Mutex<bytes::BytesMut>>,
-    receiver: broadcast::Receiver<bytes::Bytes>,
+pub struct StreamOptions {
+    pub hint: Hint,
+    /// How long the feed says the stream is, for when the media doesn't say
+    pub duration: Option<Duration>,
+    /// Where to start playing from
+    pub start: Duration,
+    /// Chapters to use if the media has none, such as ones listed in the show notes
+    pub chapters: Chapters,
+    /// Whether to look for MP4 chapters, which symphonia doesn't read itself
+    pub mp4_chapters: ChapterScan,
 }
 
-impl Downloader {
-    pub async fn start(url: reqwest::Url) -> Result<Self, Box<dyn std::error::Error>> {
-        let (tx, receiver) = broadcast::channel(1000);
-        let mut response = reqwest::get(url).await?;
-        let full_size = response.content_length().unwrap();
-        let mut dup_rx = tx.subscribe();
-        let _ = tokio::task::spawn(async move {
-            while let Some(chunked) = response.chunk().await.unwrap() {
-                let _ = tx.send(chunked);
-            }
-        });
-        let streamed_bytes = std::sync::Arc::new(tokio::sync::Mutex::new(bytes::BytesMut::new()));
-        let cloned_bytes = std::sync::Arc::clone(&streamed_bytes);
-        let _ = tokio::task::spawn(async move {
-            while let Ok(new_bytes) = dup_rx.recv().await {
-                let mut bytes = cloned_bytes.lock().await;
-                bytes.extend_from_slice(&new_bytes);
-            }
-        });
-        Ok(Self {
-            full_size,
-            streamed_bytes,
-            receiver,
-        })
-    }
+/// How far the skip controls jump
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SkipAmounts {
+    pub forward: Duration,
+    pub back: Duration,
+}
 
-    pub async fn save_file<P: AsRef<std::path::Path>>(&self, file_path: P) {
-        let mut file = tokio::fs::File::create(file_path.as_ref()).await.unwrap();
-        let mut new_rx = self.receiver.resubscribe();
-        let bytes = self.streamed_bytes.lock().await;
-        file.write_all(&bytes).await.unwrap();
-        drop(bytes);
-        loop {
-            match new_rx.recv().await {
-                Ok(data) => file.write_all(&data).await.unwrap(),
-                Err(broadcast::error::RecvError::Closed) => {
-                    if file.metadata().await.unwrap().len() < self.full_size {
-                        unimplemented!()
-                    }
-                    break;
-                }
-                Err(_) => break,
-            }
+impl Default for SkipAmounts {
+    fn default() -> Self {
+        SkipAmounts {
+            forward: Duration::from_secs(30),
+            back: Duration::from_secs(15),
         }
     }
 }
 
-#[derive(Debug)]
 pub enum PlaybackInstructions {
-    NewStream(BytesWrapper),
+    NewStream(Box<dyn MediaSource>, StreamOptions),
     Pause,
+    /// Stop playing the current stream altogether
+    Stop,
     Play,
+    /// Change the tempo without changing the pitch, from 0.5x to 3x
     Speed(f32),
+    /// Turn shortening long silences on or off
+    TrimSilence(bool),
     Seek(u64),
+    /// Seek forwards, or backwards if negative, by this many milliseconds
+    SeekBy(i64),
+    SkipForward,
+    SkipBack,
+    SetSkipAmounts(SkipAmounts),
+    NextChapter,
+    /// Go back to the start of the chapter that is playing, or to the one before if it has only
+    /// just started
+    PreviousChapter,
+    /// Go to the chapter with this index, counting from 0
+    JumpToChapter(usize),
+    /// Chapters that the feed publishes for the stream, to merge with any in the media
+    SetChapters(Chapters),
+}
+
+impl std::fmt::Debug for PlaybackInstructions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PlaybackInstructions::NewStream(source, options) => f
+                .debug_struct("NewStream")
+                .field("byte_len", &source.byte_len())
+                .field("options", options)
+                .finish(),
+            PlaybackInstructions::Pause => f.write_str("Pause"),
+            PlaybackInstructions::Stop => f.write_str("Stop"),
+            PlaybackInstructions::Play => f.write_str("Play"),
+            PlaybackInstructions::Speed(speed) => f.debug_tuple("Speed").field(speed).finish(),
+            PlaybackInstructions::TrimSilence(enabled) => {
+                f.debug_tuple("TrimSilence").field(enabled).finish()
+            }
+            PlaybackInstructions::Seek(to) => f.debug_tuple("Seek").field(to).finish(),
+            PlaybackInstructions::SeekBy(by) => f.debug_tuple("SeekBy").field(by).finish(),
+            PlaybackInstructions::SkipForward => f.write_str("SkipForward"),
+            PlaybackInstructions::SkipBack => f.write_str("SkipBack"),
+            PlaybackInstructions::SetSkipAmounts(amounts) => {
+                f.debug_tuple("SetSkipAmounts").field(amounts).finish()
+            }
+            PlaybackInstructions::NextChapter => f.write_str("NextChapter"),
+            PlaybackInstructions::PreviousChapter => f.write_str("PreviousChapter"),
+            PlaybackInstructions::JumpToChapter(index) => {
+                f.debug_tuple("JumpToChapter").field(index).finish()
+            }
+            PlaybackInstructions::SetChapters(chapters) => {
+                f.debug_tuple("SetChapters").field(&chapters.len()).finish()
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum ReceivedData {
-    NewTimestamp(u64),
+    /// How far playback has got, sent every so often rather than for every packet
+    NewTimestamp(Duration),
     NewMetadata(MetadataRevision),
+    /// How long the stream is, from the media itself or else from the feed
+    Duration(Duration),
+    /// Whether playback is waiting for the stream to catch up
+    Buffering(bool),
+    /// The chapters of the stream that is playing
+    Chapters(Chapters),
+    /// Playback moved into the chapter with this index
+    ChapterChanged(usize, Chapter),
+    /// Where a seek ended up, or why it failed
+    Seeked(Result<Duration, String>),
+    /// Silence trimming shortened a gap by this much
+    TimeSaved(Duration),
+    /// Playback was paused at this position
+    Paused(Duration),
+    /// Playback was stopped at this position
+    Stopped(Duration),
 }
 
 async fn stream_podcast(
+    client: &http::HttpClient,
     send: mpsc::Sender<PlaybackInstructions>,
+    events: mpsc::Sender<ReceivedData>,
+    episode: &Episode,
     stream: Stream,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let (bytes_send, bytes_recv) = mpsc::channel(1);
-    send.send(PlaybackInstructions::NewStream(BytesWrapper {
-        recv: bytes_recv,
-        bytes: bytes::BytesMut::new(), // response.bytes().await?,
-        cursor: 0,
-    }))
-    .await?;
-    match stream {
+    hint: Hint,
+    mp4: bool,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let podcast_id = episode.podcast_id();
+    // Streams are only searched as far as the media, so that playback doesn't wait for the whole
+    // file to download
+    let mp4_chapters = match (&stream, mp4) {
+        (_, false) => ChapterScan::Skip,
+        (Stream::Url(_), true) => ChapterScan::BeforeMedia,
+        (Stream::File(_), true) => ChapterScan::Anywhere,
+    };
+    let credentials = db::run(move |con| Credentials::for_podcast(con, podcast_id)).await?;
+    // Both are read straight from disk, so nothing but the decoder's buffer is kept in memory
+    let source: Box<dyn MediaSource> = match stream {
         Stream::Url(url) => {
-            let mut download = Downloader::start(url).await?;
-            while let Ok(chunk) = download.receiver.recv().await {
-                bytes_send.send(chunk).await.unwrap();
-            }
-            download.save_file("test.mp3").await;
+            let reader = cache::stream(client, url, credentials.clone()).await?;
+            let mut buffering = reader.buffering();
+            let _ = tokio::task::spawn(async move {
+                while buffering.changed().await.is_ok() {
+                    let state = *buffering.borrow_and_update();
+                    if events.send(ReceivedData::Buffering(state)).await.is_err() {
+                        break;
+                    }
+                }
+            });
+            Box::new(reader)
         }
-        Stream::File(file_path) => {
-            let mut file = tokio::fs::File::open(file_path).await?;
-            let mut bytes = vec![];
-            let maybe_chunk = file.read_to_end(&mut bytes).await;
-            if let Ok(_) = maybe_chunk {
-                bytes_send.send(bytes.into()).await.unwrap();
+        Stream::File(file_path) => Box::new(std::fs::File::open(file_path)?),
+    };
+    let millis = |millis: Option<i32>| millis.and_then(|millis| u64::try_from(millis).ok());
+    let options = StreamOptions {
+        hint,
+        // Fall back to what the feed says if the media doesn't say how long it is
+        duration: millis(episode.duration()).map(Duration::from_millis),
+        start: Duration::from_millis(millis(Some(episode.play_position())).unwrap_or(0)),
+        chapters: Chapters::from_show_notes(&sarcast_data::chapters::show_note_chapters(
+            episode.description().unwrap_or_default(),
+        )),
+        mp4_chapters,
+    };
+    send.send(PlaybackInstructions::NewStream(source, options))
+        .await?;
+    // Chapters published in the feed arrive once the stream is playing
+    if let Some(uri) = episode.chapters_uri() {
+        let (client, uri) = (client.clone(), uri.to_owned());
+        let _ = tokio::task::spawn(async move {
+            match chapters::fetch(&client, &uri, credentials.as_ref()).await {
+                Ok(chapters) => {
+                    let _ = send.send(PlaybackInstructions::SetChapters(chapters)).await;
+                }
+                Err(e) => tracing::warn!(
+                    "Failed to load the chapters from {}: {}",
+                    redact_uri(&uri),
+                    e
+                ),
             }
-        }
+        });
     }
     // send.send(PlaybackInstructions::Speed(2.0)).await?;
     // send.send(PlaybackInstructions::Play).await?;
     Ok(())
 }
-
-#[derive(Debug)]
-pub struct BytesWrapper {
-    recv: mpsc::Receiver<bytes::Bytes>,
-    bytes: bytes::BytesMut,
-    cursor: u64,
-}
-
-impl std::io::Read for BytesWrapper {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let cursor = self.cursor as usize;
-        let read_length = buf.len();
-        while cursor + read_length > self.bytes.len() {
-            use bytes::BufMut;
-            if let Some(res) = self.recv.blocking_recv() {
-                self.bytes.put(res);
-            }
-        }
-        buf[..read_length].copy_from_slice(&self.bytes[cursor..cursor + read_length]);
-        self.cursor += read_length as u64;
-        tracing::debug!("{} / {}", self.cursor, self.bytes.len());
-        Ok(read_length)
-    }
-}
-
-impl std::io::Seek for BytesWrapper {
-    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
-        match pos {
-            std::io::SeekFrom::End(end) => {
-                if -end > self.bytes.len() as i64 {
-                    return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, ""));
-                }
-                self.cursor = (self.bytes.len() as u64).wrapping_add(end as u64);
-            }
-            std::io::SeekFrom::Start(start) => {
-                self.cursor = (self.bytes.len() as u64).min(self.cursor + start);
-            }
-            std::io::SeekFrom::Current(current) => {
-                if -current > self.cursor as i64 {
-                    return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, ""));
-                }
-                self.cursor = self.cursor.wrapping_add(current as u64);
-            }
-        }
-        Ok(self.cursor)
-    }
-}
-
-impl MediaSource for BytesWrapper {
-    fn is_seekable(&self) -> bool {
-        true
-    }
-    fn byte_len(&self) -> Option<u64> {
-        None
-    }
-}
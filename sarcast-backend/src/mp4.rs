@@ -0,0 +1,525 @@
+use std::io::{self, Read, Seek, SeekFrom};
+use std::time::Duration;
+
+use crate::chapters::{Chapter, Chapters};
+
+/// More chapters than any real file has, so that a corrupt count can't run away
+const MAX_CHAPTERS: u32 = 10_000;
+
+/// Whether to look for the chapters of a stream, which depends on what it is and where it's read
+/// from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChapterScan {
+    /// It isn't an MP4 file, so there is nothing to look for
+    Skip,
+    /// Only look if the chapters come before the media, as finding them after it would mean
+    /// waiting for the whole file to download
+    BeforeMedia,
+    /// Look wherever the chapters are, as the whole file is at hand
+    Anywhere,
+}
+
+/// The header of an MP4 box (atom)
+struct BoxHeader {
+    kind: [u8; 4],
+    /// Where the contents of the box start
+    start: u64,
+    /// Where the box ends, which may be `u64::MAX` for a box that runs to the end of the file
+    end: u64,
+}
+
+/// What is needed from a track to read the chapter track that it refers to
+#[derive(Default)]
+struct Track {
+    id: u32,
+    /// The IDs of the tracks that hold this track's chapters
+    chapter_tracks: Vec<u32>,
+    timescale: u32,
+    /// How many samples each run of samples has, and how long each of them lasts
+    sample_durations: Vec<(u32, u32)>,
+    /// The chunk that each run of chunks starts at, counting from 1, and how many samples each
+    /// of its chunks has
+    samples_per_chunk: Vec<(u32, u32)>,
+    sample_sizes: Vec<u32>,
+    chunk_offsets: Vec<u64>,
+}
+
+/// Read the chapters of an MP4 file, such as an `.m4b` audiobook or an AAC podcast.
+///
+/// Chapters are taken from a Nero `chpl` box if there is one, or else from a text chapter track
+/// like the ones that Apple's tools write. Anything that isn't an MP4 file has no chapters, and
+/// neither does one whose chapters are after the media when `scan` is
+/// [`ChapterScan::BeforeMedia`]. The reader is left wherever the chapters were found, so it should
+/// be rewound before it is read from again.
+pub(crate) fn read_chapters<R: Read + Seek>(
+    reader: &mut R,
+    scan: ChapterScan,
+) -> io::Result<Chapters> {
+    if scan == ChapterScan::Skip {
+        return Ok(Chapters::default());
+    }
+    let _ = reader.seek(SeekFrom::Start(0))?;
+    let Some(first) = next_box(reader, u64::MAX)? else {
+        return Ok(Chapters::default());
+    };
+    if &first.kind != b"ftyp" {
+        return Ok(Chapters::default());
+    }
+
+    let mut position = first.end;
+    let moov = loop {
+        let _ = reader.seek(SeekFrom::Start(position))?;
+        match next_box(reader, u64::MAX)? {
+            Some(header) if &header.kind == b"moov" => break header,
+            Some(header) if &header.kind == b"mdat" && scan == ChapterScan::BeforeMedia => {
+                return Ok(Chapters::default());
+            }
+            Some(header) if header.end != u64::MAX => position = header.end,
+            _ => return Ok(Chapters::default()),
+        }
+    };
+
+    let mut nero = vec![];
+    let mut tracks = vec![];
+    for header in children(reader, &moov)? {
+        match &header.kind {
+            b"udta" => {
+                for child in children(reader, &header)? {
+                    if &child.kind == b"chpl" {
+                        let _ = reader.seek(SeekFrom::Start(child.start))?;
+                        nero = read_chpl(reader)?;
+                    }
+                }
+            }
+            b"trak" => tracks.push(read_track(reader, &header)?),
+            _ => {}
+        }
+    }
+    if !nero.is_empty() {
+        return Ok(chapters(nero));
+    }
+
+    let chapter_track = tracks
+        .iter()
+        .flat_map(|track| &track.chapter_tracks)
+        .find_map(|id| tracks.iter().find(|track| track.id == *id));
+    match chapter_track {
+        Some(track) => Ok(chapters(read_text_samples(reader, track)?)),
+        None => Ok(Chapters::default()),
+    }
+}
+
+/// Turn where each chapter starts into chapters that run until the next one starts
+fn chapters(mut marks: Vec<(Duration, Option<String>)>) -> Chapters {
+    marks.sort_by_key(|(start, _)| *start);
+    let ends: Vec<Option<Duration>> = marks
+        .iter()
+        .skip(1)
+        .map(|(start, _)| Some(*start))
+        .chain([None])
+        .collect();
+    marks
+        .into_iter()
+        .zip(ends)
+        .map(|((start, title), end)| Chapter {
+            title: title.filter(|title| !title.trim().is_empty()),
+            start,
+            end,
+            image: None,
+            url: None,
+            hidden: false,
+            derived: false,
+        })
+        .collect()
+}
+
+/// Read a Nero `chpl` box, whose start times are in units of 100ns
+fn read_chpl<R: Read>(reader: &mut R) -> io::Result<Vec<(Duration, Option<String>)>> {
+    let version = read_u32(reader)? >> 24;
+    if version > 0 {
+        let _reserved = read_u32(reader)?;
+    }
+    let count = read_u8(reader)?;
+    let mut marks = vec![];
+    for _ in 0..count {
+        let start = read_u64(reader)?;
+        let mut title = vec![0; usize::from(read_u8(reader)?)];
+        reader.read_exact(&mut title)?;
+        marks.push((
+            Duration::from_nanos(start.saturating_mul(100)),
+            Some(String::from_utf8_lossy(&title).into_owned()),
+        ));
+    }
+    Ok(marks)
+}
+
+fn read_track<R: Read + Seek>(reader: &mut R, parent: &BoxHeader) -> io::Result<Track> {
+    let mut track = Track::default();
+    let mut pending = children(reader, parent)?;
+    while let Some(header) = pending.pop() {
+        let _ = reader.seek(SeekFrom::Start(header.start))?;
+        match &header.kind {
+            b"mdia" | b"minf" | b"stbl" | b"tref" => pending.extend(children(reader, &header)?),
+            b"tkhd" => {
+                let version = read_u32(reader)? >> 24;
+                // Skip the creation and modification times
+                let _ = reader.seek(SeekFrom::Current(if version == 1 { 16 } else { 8 }))?;
+                track.id = read_u32(reader)?;
+            }
+            b"chap" => {
+                while reader.stream_position()?.saturating_add(4) <= header.end {
+                    track.chapter_tracks.push(read_u32(reader)?);
+                }
+            }
+            b"mdhd" => {
+                let version = read_u32(reader)? >> 24;
+                let _ = reader.seek(SeekFrom::Current(if version == 1 { 16 } else { 8 }))?;
+                track.timescale = read_u32(reader)?;
+            }
+            b"stts" => {
+                let _ = read_u32(reader)?;
+                for _ in 0..read_u32(reader)?.min(MAX_CHAPTERS) {
+                    track
+                        .sample_durations
+                        .push((read_u32(reader)?, read_u32(reader)?));
+                }
+            }
+            b"stsc" => {
+                let _ = read_u32(reader)?;
+                for _ in 0..read_u32(reader)?.min(MAX_CHAPTERS) {
+                    let (first_chunk, samples) = (read_u32(reader)?, read_u32(reader)?);
+                    let _description = read_u32(reader)?;
+                    track.samples_per_chunk.push((first_chunk, samples));
+                }
+            }
+            b"stsz" => {
+                let _ = read_u32(reader)?;
+                let size = read_u32(reader)?;
+                let count = read_u32(reader)?.min(MAX_CHAPTERS);
+                for _ in 0..count {
+                    let size = if size == 0 { read_u32(reader)? } else { size };
+                    track.sample_sizes.push(size);
+                }
+            }
+            b"stco" | b"co64" => {
+                let _ = read_u32(reader)?;
+                for _ in 0..read_u32(reader)?.min(MAX_CHAPTERS) {
+                    let offset = if &header.kind == b"co64" {
+                        read_u64(reader)?
+                    } else {
+                        u64::from(read_u32(reader)?)
+                    };
+                    track.chunk_offsets.push(offset);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(track)
+}
+
+/// Read the titles from a text track, and when each of them starts
+fn read_text_samples<R: Read + Seek>(
+    reader: &mut R,
+    track: &Track,
+) -> io::Result<Vec<(Duration, Option<String>)>> {
+    if track.timescale == 0 {
+        return Ok(vec![]);
+    }
+
+    // Work out where each sample is from the chunks that they are grouped into
+    let mut offsets = vec![];
+    for (index, chunk_offset) in track.chunk_offsets.iter().enumerate() {
+        let chunk = u32::try_from(index + 1).unwrap_or(u32::MAX);
+        let samples = track
+            .samples_per_chunk
+            .iter()
+            .rev()
+            .find(|(first_chunk, _)| *first_chunk <= chunk)
+            .map_or(0, |(_, samples)| *samples);
+        let mut offset = *chunk_offset;
+        for _ in 0..samples {
+            let Some(size) = track.sample_sizes.get(offsets.len()) else {
+                break;
+            };
+            offsets.push((offset, *size));
+            offset = offset.saturating_add(u64::from(*size));
+        }
+    }
+
+    let starts = track
+        .sample_durations
+        .iter()
+        .flat_map(|(count, duration)| (0..*count).map(move |_| u64::from(*duration)))
+        .scan(0_u64, |time, duration| {
+            let start = *time;
+            *time = time.saturating_add(duration);
+            Some(start)
+        });
+
+    let mut marks = vec![];
+    for ((offset, size), start) in offsets.into_iter().zip(starts) {
+        let title = if size >= 2 {
+            let _ = reader.seek(SeekFrom::Start(offset))?;
+            let length = u32::from(read_u16(reader)?).min(size - 2);
+            let mut text = vec![0; length as usize];
+            reader.read_exact(&mut text)?;
+            Some(decode_text(&text))
+        } else {
+            None
+        };
+        let millis = start.saturating_mul(1000) / u64::from(track.timescale);
+        marks.push((Duration::from_millis(millis), title));
+    }
+    Ok(marks)
+}
+
+/// Decode the text of a sample, which is UTF-16 if it starts with a byte order mark and UTF-8
+/// otherwise
+fn decode_text(text: &[u8]) -> String {
+    match text {
+        [0xfe, 0xff, rest @ ..] => {
+            let units: Vec<u16> = rest
+                .chunks_exact(2)
+                .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        _ => String::from_utf8_lossy(text).into_owned(),
+    }
+}
+
+/// Read the boxes directly inside `parent`
+fn children<R: Read + Seek>(reader: &mut R, parent: &BoxHeader) -> io::Result<Vec<BoxHeader>> {
+    let mut children = vec![];
+    let mut position = parent.start;
+    while position < parent.end {
+        let _ = reader.seek(SeekFrom::Start(position))?;
+        let Some(child) = next_box(reader, parent.end)? else {
+            break;
+        };
+        position = child.end;
+        children.push(child);
+    }
+    Ok(children)
+}
+
+/// Read the header of the box at the current position, or `None` if there are no more boxes
+/// before `end`
+fn next_box<R: Read + Seek>(reader: &mut R, end: u64) -> io::Result<Option<BoxHeader>> {
+    let start = reader.stream_position()?;
+    let size = match read_u32(reader) {
+        Ok(size) => u64::from(size),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut kind = [0; 4];
+    reader.read_exact(&mut kind)?;
+    let (size, header) = match size {
+        0 => (None, 8),
+        1 => (Some(read_u64(reader)?), 16),
+        size => (Some(size), 8),
+    };
+    let box_end = match size {
+        Some(size) if size < header => return Ok(None),
+        Some(size) => start.saturating_add(size).min(end),
+        None => end,
+    };
+    Ok(Some(BoxHeader {
+        kind,
+        start: start.saturating_add(header),
+        end: box_end,
+    }))
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> io::Result<u8> {
+    let mut bytes = [0; 1];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+fn read_u16<R: Read>(reader: &mut R) -> io::Result<u16> {
+    let mut bytes = [0; 2];
+    reader.read_exact(&mut bytes)?;
+    Ok(u16::from_be_bytes(bytes))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    fn mp4_box(kind: [u8; 4], body: &[u8]) -> Vec<u8> {
+        let size = u32::try_from(body.len() + 8).unwrap();
+        [&size.to_be_bytes()[..], &kind, body].concat()
+    }
+
+    fn ftyp() -> Vec<u8> {
+        mp4_box(*b"ftyp", b"M4A \0\0\0\0M4A mp42isom")
+    }
+
+    /// A `chpl` box with a chapter at each of `marks`, in milliseconds
+    fn chpl(version: u8, marks: &[(u64, &str)]) -> Vec<u8> {
+        let mut body = vec![version, 0, 0, 0];
+        if version > 0 {
+            body.extend([0; 4]);
+        }
+        body.push(u8::try_from(marks.len()).unwrap());
+        for (millis, title) in marks {
+            body.extend((millis * 10_000).to_be_bytes());
+            body.push(u8::try_from(title.len()).unwrap());
+            body.extend(title.as_bytes());
+        }
+        mp4_box(*b"chpl", &body)
+    }
+
+    fn nero_file(version: u8) -> Vec<u8> {
+        let marks = [(0, "Intro"), (90_500, "Follow-up"), (1_800_000, "")];
+        let moov = mp4_box(*b"moov", &mp4_box(*b"udta", &chpl(version, &marks)));
+        [ftyp(), moov].concat()
+    }
+
+    fn full_box(kind: [u8; 4], fields: &[u32]) -> Vec<u8> {
+        let mut body = vec![0; 4];
+        for field in fields {
+            body.extend(field.to_be_bytes());
+        }
+        mp4_box(kind, &body)
+    }
+
+    /// An audio track that refers to a text track holding `titles`, each `duration` long in a
+    /// timescale of 1000, stored from `offset` in the file
+    fn text_tracks(titles: &[&str], duration: u32, offset: u32) -> Vec<u8> {
+        let count = u32::try_from(titles.len()).unwrap();
+        let sizes = titles
+            .iter()
+            .map(|title| u32::try_from(title.len() + 2).unwrap());
+        let stbl = [
+            full_box(*b"stts", &[1, count, duration]),
+            full_box(*b"stsc", &[1, 1, count, 1]),
+            full_box(
+                *b"stsz",
+                &[[0, count].as_slice(), &sizes.collect::<Vec<_>>()].concat(),
+            ),
+            full_box(*b"stco", &[1, offset]),
+        ]
+        .concat();
+        let minf = mp4_box(*b"minf", &mp4_box(*b"stbl", &stbl));
+        let mdia = mp4_box(
+            *b"mdia",
+            &[full_box(*b"mdhd", &[0, 0, 1000]), minf].concat(),
+        );
+        let text = mp4_box(*b"trak", &[full_box(*b"tkhd", &[0, 0, 2]), mdia].concat());
+        let tref = mp4_box(*b"tref", &mp4_box(*b"chap", &2_u32.to_be_bytes()));
+        let audio = mp4_box(*b"trak", &[full_box(*b"tkhd", &[0, 0, 1]), tref].concat());
+        [audio, text].concat()
+    }
+
+    fn text_track_file(titles: &[&str]) -> Vec<u8> {
+        let samples: Vec<u8> = titles
+            .iter()
+            .flat_map(|title| {
+                let length = u16::try_from(title.len()).unwrap().to_be_bytes();
+                [&length[..], title.as_bytes()].concat()
+            })
+            .collect();
+        // The track is the same size wherever its samples are, so measure it first
+        let moov_length = mp4_box(*b"moov", &text_tracks(titles, 0, 0)).len();
+        let offset = u32::try_from(ftyp().len() + moov_length + 8).unwrap();
+        let moov = mp4_box(*b"moov", &text_tracks(titles, 45_000, offset));
+        [ftyp(), moov, mp4_box(*b"mdat", &samples)].concat()
+    }
+
+    #[test]
+    pub(crate) fn nero_chapters() {
+        for version in [0, 1] {
+            let chapters =
+                read_chapters(&mut Cursor::new(nero_file(version)), ChapterScan::Anywhere).unwrap();
+            assert_eq!(chapters.len(), 3);
+            let first = chapters.get(0).unwrap();
+            assert_eq!(first.title.as_deref(), Some("Intro"));
+            assert_eq!(first.end, Some(Duration::from_millis(90_500)));
+            let second = chapters.get(1).unwrap();
+            assert_eq!(second.title.as_deref(), Some("Follow-up"));
+            assert_eq!(second.start, Duration::from_millis(90_500));
+            let last = chapters.get(2).unwrap();
+            assert_eq!(last.title, None);
+            assert_eq!(last.end, None);
+        }
+    }
+
+    #[test]
+    pub(crate) fn text_track_chapters() {
+        let file = text_track_file(&["Cold open", "News", "Picks"]);
+        let chapters = read_chapters(&mut Cursor::new(file), ChapterScan::Anywhere).unwrap();
+        assert_eq!(chapters.len(), 3);
+        let titles: Vec<_> = chapters
+            .iter()
+            .map(|chapter| chapter.title.clone())
+            .collect();
+        assert_eq!(
+            titles,
+            [
+                Some("Cold open".into()),
+                Some("News".into()),
+                Some("Picks".into())
+            ]
+        );
+        assert_eq!(chapters.get(2).unwrap().start, Duration::from_secs(90));
+    }
+
+    #[test]
+    pub(crate) fn broken_files() {
+        // Cut off part way through the chapters
+        let mut file = nero_file(0);
+        file.truncate(file.len() - 12);
+        assert!(read_chapters(&mut Cursor::new(file), ChapterScan::Anywhere).is_err());
+
+        // Samples that claim to be far bigger than the file, or past the end of it
+        let mut file = text_track_file(&["Cold open", "News"]);
+        let stsz = file.windows(4).position(|kind| kind == b"stsz").unwrap();
+        file[stsz + 16..stsz + 20].copy_from_slice(&u32::MAX.to_be_bytes());
+        file[stsz + 20..stsz + 24].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(read_chapters(&mut Cursor::new(file), ChapterScan::Anywhere).is_err());
+
+        let empty = read_chapters(&mut Cursor::new(vec![]), ChapterScan::Anywhere).unwrap();
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    pub(crate) fn other_formats() {
+        let mp3 = [&b"ID3\x04\0\0\0\0\0\0"[..], &[0xff, 0xfb, 0x90, 0x64][..]].concat();
+        assert!(read_chapters(&mut Cursor::new(mp3), ChapterScan::Anywhere)
+            .unwrap()
+            .is_empty());
+        let wav = [&b"RIFF"[..], &36_u32.to_le_bytes()[..], b"WAVEfmt "].concat();
+        assert!(read_chapters(&mut Cursor::new(wav), ChapterScan::Anywhere)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    pub(crate) fn chapters_after_the_media() {
+        let marks = [(0, "Intro"), (90_500, "Follow-up")];
+        let moov = mp4_box(*b"moov", &mp4_box(*b"udta", &chpl(0, &marks)));
+        let file = [ftyp(), mp4_box(*b"mdat", &[0; 64]), moov].concat();
+        let streamed = read_chapters(&mut Cursor::new(&file), ChapterScan::BeforeMedia).unwrap();
+        assert!(streamed.is_empty());
+        let downloaded = read_chapters(&mut Cursor::new(&file), ChapterScan::Anywhere).unwrap();
+        assert_eq!(downloaded.len(), 2);
+        let skipped = read_chapters(&mut Cursor::new(nero_file(0)), ChapterScan::Skip).unwrap();
+        assert!(skipped.is_empty());
+    }
+}
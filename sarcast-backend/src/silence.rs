@@ -0,0 +1,254 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rodio::Source;
+use tokio::sync::mpsc::Sender;
+
+use crate::ReceivedData;
+
+/// How loud audio has to be to not count as silence, in dBFS
+const THRESHOLD_DBFS: f32 = -45.0;
+/// How much of any gap is kept, so that speech still has room to breathe
+const MIN_GAP: Duration = Duration::from_millis(300);
+/// How much audio the loudness is measured over at a time, which is also how long the
+/// crossfade over a shortened gap is
+const BLOCK: Duration = Duration::from_millis(10);
+
+/// Whether silence trimming is on, which can be changed from another thread while audio plays
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Trimming(Arc<AtomicBool>);
+
+impl Trimming {
+    pub(crate) fn enabled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set(&self, enabled: bool) {
+        self.0.store(enabled, Ordering::Relaxed);
+    }
+}
+
+/// Shortens gaps in a source that are quieter than [`THRESHOLD_DBFS`] down to [`MIN_GAP`].
+///
+/// The end of a shortened gap is crossfaded into the audio that follows, so that cutting it
+/// doesn't click. The time that each gap saves is reported as [`ReceivedData::TimeSaved`].
+pub(crate) struct TrimSilence<S> {
+    inner: S,
+    trimming: Trimming,
+    tx: Sender<ReceivedData>,
+    channels: u16,
+    sample_rate: u32,
+    /// How long the current gap has been played for, in frames
+    gap: usize,
+    /// The latest block of a gap that is being cut, held back to crossfade from
+    held: Option<Vec<i16>>,
+    /// How many frames have been cut from the current gap
+    cut: u64,
+    output: VecDeque<i16>,
+    finished: bool,
+}
+
+impl<S> TrimSilence<S>
+where
+    S: Source<Item = i16>,
+{
+    pub(crate) fn new(inner: S, trimming: Trimming, tx: Sender<ReceivedData>) -> Self {
+        TrimSilence {
+            channels: inner.channels().max(1),
+            sample_rate: inner.sample_rate().max(1),
+            inner,
+            trimming,
+            tx,
+            gap: 0,
+            held: None,
+            cut: 0,
+            output: VecDeque::new(),
+            finished: false,
+        }
+    }
+
+    /// How many frames `duration` is
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    fn frames(&self, duration: Duration) -> usize {
+        (duration.as_secs_f64() * f64::from(self.sample_rate)).round() as usize
+    }
+
+    /// Read the next block from the source, which is shorter than usual at the end
+    fn read_block(&mut self) -> Vec<i16> {
+        self.channels = self.inner.channels().max(1);
+        self.sample_rate = self.inner.sample_rate().max(1);
+        let samples = self.frames(BLOCK).max(1) * usize::from(self.channels);
+        self.inner.by_ref().take(samples).collect()
+    }
+
+    /// Whether `block` is quieter than [`THRESHOLD_DBFS`]
+    #[allow(clippy::cast_precision_loss)]
+    fn is_silent(block: &[i16]) -> bool {
+        let threshold = f64::from(i16::MAX) * 10_f64.powf(f64::from(THRESHOLD_DBFS) / 20.0);
+        let energy: f64 = block
+            .iter()
+            .map(|sample| f64::from(*sample) * f64::from(*sample))
+            .sum();
+        energy / block.len().max(1) as f64 <= threshold * threshold
+    }
+
+    /// Report how much time the gap that just ended saved
+    fn end_gap(&mut self) {
+        self.gap = 0;
+        if self.cut > 0 {
+            let saved = Duration::from_secs(self.cut) / self.sample_rate;
+            let _ = self.tx.try_send(ReceivedData::TimeSaved(saved));
+            self.cut = 0;
+        }
+    }
+
+    /// Work out what to play next, or return `false` once the source has run out
+    fn step(&mut self) -> bool {
+        let block = self.read_block();
+        if block.is_empty() {
+            self.finished = true;
+            if let Some(held) = self.held.take() {
+                self.output.extend(held);
+            }
+            self.end_gap();
+            return !self.output.is_empty();
+        }
+        let frames = block.len() / usize::from(self.channels);
+
+        if !Self::is_silent(&block) || !self.trimming.enabled() {
+            if let Some(held) = self.held.take() {
+                self.output.extend(crossfade(&held, &block));
+                self.cut += held.len() as u64 / u64::from(self.channels);
+            } else {
+                self.output.extend(block);
+            }
+            self.end_gap();
+        } else if self.gap + frames <= self.frames(MIN_GAP) {
+            self.gap += frames;
+            self.output.extend(block);
+        } else {
+            // Only the latest block of the gap is kept, to fade out of when it ends
+            if let Some(held) = self.held.replace(block) {
+                self.cut += held.len() as u64 / u64::from(self.channels);
+            }
+        }
+        true
+    }
+}
+
+/// Fade from `from` into `to`, over the length of `to`
+#[allow(clippy::cast_precision_loss, clippy::cast_possible_truncation)]
+fn crossfade(from: &[i16], to: &[i16]) -> Vec<i16> {
+    let length = to.len().max(1) as f32;
+    to.iter()
+        .enumerate()
+        .map(|(index, sample)| {
+            let fade_in = index as f32 / length;
+            let faded = from.get(index).copied().map_or(0.0, f32::from) * (1.0 - fade_in);
+            (faded + f32::from(*sample) * fade_in)
+                .round()
+                .clamp(f32::from(i16::MIN), f32::from(i16::MAX)) as i16
+        })
+        .collect()
+}
+
+impl<S> Iterator for TrimSilence<S>
+where
+    S: Source<Item = i16>,
+{
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        while self.output.is_empty() {
+            if self.finished || !self.step() {
+                return None;
+            }
+        }
+        self.output.pop_front()
+    }
+}
+
+impl<S> Source for TrimSilence<S>
+where
+    S: Source<Item = i16>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        // Blocks are all the same length until the end, and the channels and sample rate only
+        // change between them
+        if !self.output.is_empty() || self.finished {
+            Some(self.output.len())
+        } else {
+            Some(self.frames(BLOCK).max(1) * usize::from(self.channels))
+        }
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        // Gaps are only found as they are played
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rodio::buffer::SamplesBuffer;
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    const RATE: u32 = 8_000;
+
+    /// A second of a loud tone, then two seconds of silence, then another second of tone
+    fn speech_with_gap() -> Vec<i16> {
+        let tone = (0..RATE).map(|frame| if frame % 16 < 8 { 8_000 } else { -8_000 });
+        let silence = (0..RATE * 2).map(|_| 0);
+        tone.clone().chain(silence).chain(tone).collect()
+    }
+
+    fn trim(samples: &[i16], enabled: bool) -> (Vec<i16>, Vec<Duration>) {
+        let (tx, mut rx) = mpsc::channel(8);
+        let trimming = Trimming::default();
+        trimming.set(enabled);
+        let source = SamplesBuffer::new(1, RATE, samples.to_vec());
+        let mut trim = TrimSilence::new(source, trimming, tx);
+        let output: Vec<i16> = trim.by_ref().collect();
+        assert_eq!((trim.channels(), trim.sample_rate()), (1, RATE));
+        let mut saved = vec![];
+        while let Ok(received) = rx.try_recv() {
+            if let ReceivedData::TimeSaved(duration) = received {
+                saved.push(duration);
+            }
+        }
+        (output, saved)
+    }
+
+    #[test]
+    pub(crate) fn long_gaps_are_cut() {
+        let samples = speech_with_gap();
+        let (output, saved) = trim(&samples, true);
+        assert_eq!(saved.len(), 1);
+        // What is left of the gap is MIN_GAP, and the block that fades into the tone after it
+        let kept = Duration::from_secs(2).checked_sub(saved[0]).unwrap();
+        assert!(kept >= MIN_GAP && kept <= MIN_GAP + BLOCK, "{:?}", kept);
+        let cut = usize::try_from(saved[0].as_millis()).unwrap() * 8;
+        assert_eq!(output.len(), samples.len() - cut);
+        assert_eq!(output[..8_000], samples[..8_000]);
+    }
+
+    #[test]
+    pub(crate) fn disabled_passes_through() {
+        let samples = speech_with_gap();
+        let (output, saved) = trim(&samples, false);
+        assert_eq!(output, samples);
+        assert!(saved.is_empty());
+    }
+}
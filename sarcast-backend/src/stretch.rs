@@ -0,0 +1,373 @@
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+use std::iter::StepBy;
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rodio::Source;
+
+/// The slowest that playback can go
+pub(crate) const MIN_SPEED: f32 = 0.5;
+/// The fastest that playback can go
+pub(crate) const MAX_SPEED: f32 = 3.0;
+
+/// How long each of the overlapping segments that audio is cut into is
+const SEGMENT: Duration = Duration::from_millis(24);
+/// How far either side of where a segment would come from to look for a better match
+const SEARCH: Duration = Duration::from_millis(8);
+
+/// A playback speed that can be changed from another thread while audio plays
+#[derive(Debug, Clone)]
+pub(crate) struct Speed(Arc<AtomicU32>);
+
+impl Default for Speed {
+    fn default() -> Self {
+        Speed(Arc::new(AtomicU32::new(1.0_f32.to_bits())))
+    }
+}
+
+impl Speed {
+    pub(crate) fn get(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    /// Set the speed, keeping it between [`MIN_SPEED`] and [`MAX_SPEED`]
+    pub(crate) fn set(&self, speed: f32) {
+        let speed = if speed.is_finite() { speed } else { 1.0 };
+        self.0.store(
+            speed.clamp(MIN_SPEED, MAX_SPEED).to_bits(),
+            Ordering::Relaxed,
+        );
+    }
+}
+
+/// Changes the tempo of a source without changing its pitch, using WSOLA (waveform similarity
+/// overlap-add).
+///
+/// The audio is cut into overlapping segments, which are taken from the source further apart
+/// than they are played to speed it up, or closer together to slow it down. Each segment is
+/// nudged to wherever it best lines up with the one before it, so that the joins don't warble.
+pub(crate) struct TimeStretch<S> {
+    inner: S,
+    speed: Speed,
+    channels: u16,
+    sample_rate: u32,
+    /// The length of a segment, in frames
+    segment: usize,
+    /// How far a segment may be nudged, in frames
+    search: usize,
+    window: Vec<f32>,
+    /// Interleaved samples that have been read from the source and may still be used
+    input: Vec<f32>,
+    /// Each frame of `input` mixed down to mono, for lining segments up
+    mono: Vec<f32>,
+    /// Where in `input` the next segment would come from if it weren't nudged, in frames
+    next: f64,
+    /// Where in `input` the audio that followed the last segment is, in frames
+    continuation: Option<usize>,
+    /// The second half of the last segment, which the first half of the next one is added to
+    overlap: Vec<f32>,
+    /// How many frames of `input` came from the source, once it has run out
+    remaining: Option<usize>,
+    output: VecDeque<i16>,
+    finished: bool,
+}
+
+impl<S> TimeStretch<S>
+where
+    S: Source<Item = i16>,
+{
+    pub(crate) fn new(inner: S, speed: Speed) -> Self {
+        let mut stretch = TimeStretch {
+            inner,
+            speed,
+            channels: 0,
+            sample_rate: 0,
+            segment: 0,
+            search: 0,
+            window: vec![],
+            input: vec![],
+            mono: vec![],
+            next: 0.0,
+            continuation: None,
+            overlap: vec![],
+            remaining: None,
+            output: VecDeque::new(),
+            finished: false,
+        };
+        stretch.configure();
+        stretch
+    }
+
+    /// Size everything for the source's current channels and sample rate
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss,
+        clippy::cast_precision_loss
+    )]
+    fn configure(&mut self) {
+        self.channels = self.inner.channels().max(1);
+        self.sample_rate = self.inner.sample_rate().max(1);
+        let frames = |duration: Duration| {
+            (duration.as_secs_f64() * f64::from(self.sample_rate)).round() as usize
+        };
+        self.segment = (frames(SEGMENT) / 2).max(1) * 2;
+        self.search = frames(SEARCH);
+        // A periodic Hann window, so that the halves of neighbouring segments add up to exactly 1
+        self.window = (0..self.segment)
+            .map(|frame| 0.5 - 0.5 * (2.0 * PI * frame as f32 / self.segment as f32).cos())
+            .collect();
+        self.input.clear();
+        self.mono.clear();
+        self.next = 0.0;
+        self.continuation = None;
+        self.overlap = vec![0.0; self.segment / 2 * usize::from(self.channels)];
+    }
+
+    /// Read from the source until `input` holds `frames` frames, or return `false` if it runs
+    /// out first
+    fn fill(&mut self, frames: usize) -> bool {
+        let channels = usize::from(self.channels);
+        while self.mono.len() < frames {
+            let mut mixed = 0.0;
+            for _ in 0..channels {
+                let Some(sample) = self.inner.next() else {
+                    self.input.truncate(self.mono.len() * channels);
+                    return false;
+                };
+                self.input.push(f32::from(sample));
+                mixed += f32::from(sample);
+            }
+            self.mono.push(mixed);
+        }
+        true
+    }
+
+    /// Forget the first `frames` frames of `input`, which no segment can come from any more
+    #[allow(clippy::cast_precision_loss)]
+    fn discard(&mut self, frames: usize) {
+        let _ = self.input.drain(..frames * usize::from(self.channels));
+        let _ = self.mono.drain(..frames);
+        self.next -= frames as f64;
+        self.continuation = self.continuation.map(|continuation| continuation - frames);
+        self.remaining = self
+            .remaining
+            .map(|remaining| remaining.saturating_sub(frames));
+    }
+
+    /// Find where in `from..=to` a segment would line up best with the audio at `target`
+    fn best_match(&self, target: usize, from: usize, to: usize) -> usize {
+        let half = self.segment / 2;
+        let similarity = |start: usize, stride: usize| {
+            let (mut correlation, mut energy) = (0.0, 0.0);
+            for frame in (0..half).step_by(stride) {
+                let sample = self.mono[start + frame];
+                correlation += sample * self.mono[target + frame];
+                energy += sample * sample;
+            }
+            correlation / (energy + 1.0).sqrt()
+        };
+        let best = |candidates: StepBy<RangeInclusive<usize>>, stride: usize| {
+            candidates
+                .map(|start| (similarity(start, stride), start))
+                .max_by(|a, b| a.0.total_cmp(&b.0))
+                .map_or(from, |(_, start)| start)
+        };
+        // Search roughly, then closely around the best rough match, to keep the cost down
+        let rough = best((from..=to).step_by(4), 4);
+        best(
+            (rough.saturating_sub(3).max(from)..=(rough + 3).min(to)).step_by(1),
+            2,
+        )
+    }
+
+    /// Produce the next half segment of output, or return `false` once there is none left
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss,
+        clippy::cast_precision_loss
+    )]
+    fn step(&mut self) -> bool {
+        if self.remaining.is_none()
+            && (self.inner.channels().max(1) != self.channels
+                || self.inner.sample_rate().max(1) != self.sample_rate)
+        {
+            self.configure();
+        }
+
+        let channels = usize::from(self.channels);
+        let half = self.segment / 2;
+        let speed = f64::from(self.speed.get());
+        let nominal = self.next.round().max(0.0) as usize;
+        // At normal speed the segments already line up, so the search can be skipped
+        let search = match self.continuation {
+            Some(_) if (speed - 1.0).abs() > 0.001 => self.search,
+            _ => 0,
+        };
+        let needed = nominal + search + self.segment;
+        if self.remaining.is_none() && !self.fill(needed) {
+            self.remaining = Some(self.mono.len());
+        }
+        if self.remaining.is_some() && self.mono.len() < needed {
+            // The source has run out, so pad what is left of it with silence, which lets the
+            // last of it go through the window like the rest
+            self.input.resize(needed * channels, 0.0);
+            self.mono.resize(needed, 0.0);
+        }
+
+        let start = match self.continuation {
+            Some(continuation) if search > 0 => self.best_match(
+                continuation,
+                nominal.saturating_sub(search),
+                nominal + search,
+            ),
+            _ => nominal,
+        };
+        // This step plays the audio that followed the last segment, so stop once that is all
+        // padding
+        let played = self.remaining.map_or(half, |remaining| {
+            remaining
+                .saturating_sub(self.continuation.unwrap_or(start))
+                .min(half)
+        });
+        // The first segment has nothing to be added to, so it isn't faded in
+        let first = self.continuation.is_none();
+        let segment = &self.input[start * channels..(start + self.segment) * channels];
+        for (index, sample) in segment.iter().enumerate() {
+            let windowed = self.window[index / channels] * sample;
+            if index < half * channels {
+                if index < played * channels {
+                    let mixed = if first {
+                        *sample
+                    } else {
+                        self.overlap[index] + windowed
+                    };
+                    self.output.push_back(to_sample(mixed));
+                }
+            } else {
+                self.overlap[index - half * channels] = windowed;
+            }
+        }
+        if played < half {
+            self.finished = true;
+            return !self.output.is_empty();
+        }
+
+        self.continuation = Some(start + half);
+        self.next += half as f64 * speed;
+        let unused = (self.next.floor().max(0.0) as usize)
+            .saturating_sub(self.search)
+            .min(start + half);
+        self.discard(unused);
+        true
+    }
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn to_sample(sample: f32) -> i16 {
+    sample
+        .round()
+        .clamp(f32::from(i16::MIN), f32::from(i16::MAX)) as i16
+}
+
+impl<S> Iterator for TimeStretch<S>
+where
+    S: Source<Item = i16>,
+{
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        while self.output.is_empty() {
+            if self.finished || !self.step() {
+                return None;
+            }
+        }
+        self.output.pop_front()
+    }
+}
+
+impl<S> Source for TimeStretch<S>
+where
+    S: Source<Item = i16>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        // Each step produces half a segment, and the channels and sample rate only change
+        // between steps
+        if !self.output.is_empty() || self.finished {
+            Some(self.output.len())
+        } else {
+            Some(self.segment / 2 * usize::from(self.channels))
+        }
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        // How long it takes to play depends on a speed that can change at any time
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rodio::buffer::SamplesBuffer;
+
+    use super::*;
+
+    /// `seconds` of noise-like audio, which no two segments of line up by chance
+    fn audio(channels: u16, sample_rate: u32, seconds: u32) -> Vec<i16> {
+        let mut state = 0x2545_f491_u32;
+        (0..sample_rate * seconds * u32::from(channels))
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                i16::try_from(state >> 20).unwrap() - 2048
+            })
+            .collect()
+    }
+
+    fn stretch(
+        samples: &[i16],
+        channels: u16,
+        sample_rate: u32,
+        speed: f32,
+    ) -> (u16, u32, Vec<i16>) {
+        let source = SamplesBuffer::new(channels, sample_rate, samples.to_vec());
+        let setting = Speed::default();
+        setting.set(speed);
+        let mut stretch = TimeStretch::new(source, setting);
+        let output: Vec<i16> = stretch.by_ref().collect();
+        (stretch.channels(), stretch.sample_rate(), output)
+    }
+
+    #[test]
+    pub(crate) fn normal_speed_passes_through() {
+        let samples = audio(2, 44_100, 1);
+        let (channels, sample_rate, output) = stretch(&samples, 2, 44_100, 1.0);
+        assert_eq!((channels, sample_rate), (2, 44_100));
+        assert_eq!(output, samples);
+
+        // Less than a segment still comes out in full
+        let (_, _, output) = stretch(&samples[..100], 2, 44_100, 1.0);
+        assert_eq!(output, &samples[..100]);
+    }
+
+    #[test]
+    #[allow(clippy::cast_precision_loss)]
+    pub(crate) fn double_speed_halves() {
+        let samples = audio(1, 22_050, 2);
+        let (channels, sample_rate, output) = stretch(&samples, 1, 22_050, 2.0);
+        assert_eq!((channels, sample_rate), (1, 22_050));
+        let ratio = output.len() as f64 / samples.len() as f64;
+        assert!((0.45..0.55).contains(&ratio), "{}", ratio);
+    }
+}
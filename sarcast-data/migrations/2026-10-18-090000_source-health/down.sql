@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE `source` DROP COLUMN `last_http_status`;
+ALTER TABLE `source` DROP COLUMN `last_error`;
+ALTER TABLE `source` DROP COLUMN `failure_count`;
+ALTER TABLE `source` DROP COLUMN `last_success`;
+ALTER TABLE `source` DROP COLUMN `last_attempt`;
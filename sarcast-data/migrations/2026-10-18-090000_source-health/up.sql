@@ -0,0 +1,7 @@
+-- Track the health of each feed so that failing feeds can be backed off and
+-- surfaced to the user.
+ALTER TABLE `source` ADD COLUMN `last_attempt` DATETIME;
+ALTER TABLE `source` ADD COLUMN `last_success` DATETIME;
+ALTER TABLE `source` ADD COLUMN `failure_count` INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE `source` ADD COLUMN `last_error` TEXT;
+ALTER TABLE `source` ADD COLUMN `last_http_status` INTEGER;
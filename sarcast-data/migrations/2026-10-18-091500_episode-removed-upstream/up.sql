@@ -0,0 +1,2 @@
+-- Record when an episode disappeared from its feed
+ALTER TABLE `episodes` ADD COLUMN `removed_upstream` DATETIME;
@@ -0,0 +1,24 @@
+-- This file should undo anything in `up.sql`
+CREATE TABLE `episodes_old` (
+    `title` TEXT NOT NULL,
+    `uri` TEXT,
+    `local_uri` TEXT,
+    `description` TEXT,
+    `epoch` INTEGER NOT NULL DEFAULT 0,
+    `length` INTEGER,
+    `duration` INTEGER,
+    `guid` TEXT,
+    `played` INTEGER,
+    `play_position` INTEGER NOT NULL DEFAULT 0,
+    `podcast_id` INTEGER NOT NULL,
+    `removed_upstream` DATETIME,
+    PRIMARY KEY (title, podcast_id)
+);
+
+INSERT INTO `episodes_old` SELECT
+    `title`, `uri`, `local_uri`, `description`, `epoch`, `length`, `duration`,
+    `guid`, `played`, `play_position`, `podcast_id`, `removed_upstream`
+FROM `episodes`;
+
+DROP TABLE `episodes`;
+ALTER TABLE `episodes_old` RENAME TO `episodes`;
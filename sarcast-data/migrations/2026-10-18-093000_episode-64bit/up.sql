@@ -0,0 +1,25 @@
+-- Widen the columns that can overflow a 32-bit integer. SQLite can't change the
+-- type of a column, so the table has to be rebuilt.
+CREATE TABLE `episodes_new` (
+    `title` TEXT NOT NULL,
+    `uri` TEXT,
+    `local_uri` TEXT,
+    `description` TEXT,
+    `epoch` BIGINT NOT NULL DEFAULT 0,
+    `length` BIGINT,
+    `duration` INTEGER,
+    `guid` TEXT,
+    `played` BIGINT,
+    `play_position` INTEGER NOT NULL DEFAULT 0,
+    `podcast_id` INTEGER NOT NULL,
+    `removed_upstream` DATETIME,
+    PRIMARY KEY (title, podcast_id)
+);
+
+INSERT INTO `episodes_new` SELECT
+    `title`, `uri`, `local_uri`, `description`, `epoch`, `length`, `duration`,
+    `guid`, `played`, `play_position`, `podcast_id`, `removed_upstream`
+FROM `episodes`;
+
+DROP TABLE `episodes`;
+ALTER TABLE `episodes_new` RENAME TO `episodes`;
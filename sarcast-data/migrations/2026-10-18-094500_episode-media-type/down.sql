@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE `episodes` DROP COLUMN `file_extension`;
+ALTER TABLE `episodes` DROP COLUMN `mime_type`;
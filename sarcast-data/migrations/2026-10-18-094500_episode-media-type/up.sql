@@ -0,0 +1,3 @@
+-- Keep the type of each enclosure, so that the decoder can be given a hint
+ALTER TABLE `episodes` ADD COLUMN `mime_type` TEXT;
+ALTER TABLE `episodes` ADD COLUMN `file_extension` TEXT;
@@ -0,0 +1,15 @@
+-- Every version of an episode's media that the feed offers, including the
+-- main `<enclosure>` and any `<podcast:alternateEnclosure>`s.
+CREATE TABLE `enclosures` (
+    `id` INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT UNIQUE,
+    `episode_title` TEXT NOT NULL,
+    `podcast_id` INTEGER NOT NULL,
+    `uri` TEXT NOT NULL,
+    `mime_type` TEXT,
+    `length` BIGINT,
+    `bitrate` INTEGER,
+    `codecs` TEXT,
+    `title` TEXT,
+    `is_default` BOOLEAN NOT NULL DEFAULT 0,
+    UNIQUE (episode_title, podcast_id, uri)
+);
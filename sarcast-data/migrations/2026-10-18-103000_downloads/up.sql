@@ -0,0 +1,16 @@
+-- The queue of episodes to download
+CREATE TABLE `downloads` (
+    `id` INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT UNIQUE,
+    `episode_title` TEXT NOT NULL,
+    `podcast_id` INTEGER NOT NULL,
+    `uri` TEXT NOT NULL,
+    `path` TEXT NOT NULL,
+    `state` TEXT NOT NULL DEFAULT 'queued',
+    `downloaded_bytes` BIGINT NOT NULL DEFAULT 0,
+    `total_bytes` BIGINT,
+    `attempts` INTEGER NOT NULL DEFAULT 0,
+    `next_attempt` DATETIME,
+    `last_error` TEXT,
+    `created` DATETIME NOT NULL,
+    UNIQUE (episode_title, podcast_id)
+);
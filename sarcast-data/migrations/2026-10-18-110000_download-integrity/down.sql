@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE `downloads` DROP COLUMN `verified`;
+ALTER TABLE `downloads` DROP COLUMN `integrity`;
+ALTER TABLE `downloads` DROP COLUMN `expected_bytes`;
+ALTER TABLE `enclosures` DROP COLUMN `integrity`;
@@ -0,0 +1,6 @@
+-- Keep what's needed to check that a download arrived intact: the length and
+-- `<podcast:integrity>` hash given by the feed, and whether the checks passed.
+ALTER TABLE `enclosures` ADD COLUMN `integrity` TEXT;
+ALTER TABLE `downloads` ADD COLUMN `expected_bytes` BIGINT;
+ALTER TABLE `downloads` ADD COLUMN `integrity` TEXT;
+ALTER TABLE `downloads` ADD COLUMN `verified` BOOLEAN NOT NULL DEFAULT 0;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE `episodes` DROP COLUMN `episode_number`;
+ALTER TABLE `episodes` DROP COLUMN `season`;
@@ -0,0 +1,4 @@
+-- The season and episode numbers given by `<itunes:season>`/`<itunes:episode>`
+-- or `<podcast:season>`/`<podcast:episode>`, used when naming downloads.
+ALTER TABLE `episodes` ADD COLUMN `season` INTEGER;
+ALTER TABLE `episodes` ADD COLUMN `episode_number` INTEGER;
@@ -0,0 +1,3 @@
+-- Downloads that the user asked for straight away, which ignore the schedule
+-- that automatic downloads are kept to.
+ALTER TABLE `downloads` ADD COLUMN `manual` BOOLEAN NOT NULL DEFAULT 0;
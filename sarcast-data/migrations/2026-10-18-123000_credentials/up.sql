@@ -0,0 +1,9 @@
+-- Secrets for private feeds, kept apart from the source so that they aren't exported or logged
+-- along with it
+CREATE TABLE `credentials` (
+    `source_id` INTEGER NOT NULL PRIMARY KEY REFERENCES `source`(`id`) ON DELETE CASCADE,
+    `username` TEXT,
+    `password` TEXT,
+    `query` TEXT,
+    `headers` TEXT
+);
@@ -0,0 +1,13 @@
+-- Whether an episode is a full episode, a trailer or a bonus, from `<itunes:episodeType>`
+ALTER TABLE `episodes` ADD COLUMN `episode_type` TEXT;
+
+-- Which episodes of a podcast to download automatically after a refresh, and when to delete
+-- them again
+CREATE TABLE `download_rules` (
+    `podcast_id` INTEGER NOT NULL PRIMARY KEY REFERENCES `podcasts`(`id`) ON DELETE CASCADE,
+    `newest_unplayed` INTEGER,
+    `title_patterns` TEXT,
+    `skip_trailers` BOOLEAN NOT NULL DEFAULT 1,
+    `delete_played_after_days` INTEGER,
+    `keep_latest` INTEGER
+);
@@ -0,0 +1,2 @@
+-- The most space that a podcast's downloads may take up, in bytes
+ALTER TABLE `podcasts` ADD COLUMN `max_download_bytes` BIGINT;
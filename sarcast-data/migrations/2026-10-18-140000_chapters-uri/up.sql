@@ -0,0 +1,2 @@
+-- Where the feed publishes the chapters of an episode, in the Podcasting 2.0 JSON format
+ALTER TABLE `episodes` ADD COLUMN `chapters_uri` TEXT;
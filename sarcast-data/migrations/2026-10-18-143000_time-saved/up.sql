@@ -0,0 +1,2 @@
+-- How much listening time silence trimming has saved on each episode, in milliseconds
+ALTER TABLE `episodes` ADD COLUMN `time_saved` BIGINT NOT NULL DEFAULT 0;
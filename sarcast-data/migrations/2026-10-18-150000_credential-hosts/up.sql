@@ -0,0 +1,2 @@
+-- Other hosts that a source's credentials may be sent to, besides the feed's own, one per line
+ALTER TABLE `credentials` ADD COLUMN `hosts` TEXT;
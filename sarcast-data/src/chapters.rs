@@ -0,0 +1,350 @@
+use serde::Deserialize;
+use std::fmt::Write;
+use std::ops::Range;
+use std::time::Duration;
+
+/// The MIME type of the Podcasting 2.0 JSON chapters format
+pub const MIME_TYPE: &str = "application/json+chapters";
+
+#[derive(Deserialize)]
+struct Document {
+    chapters: Vec<JsonChapter>,
+}
+
+/// A chapter from a Podcasting 2.0 JSON chapters file
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct JsonChapter {
+    start_time: f64,
+    end_time: Option<f64>,
+    title: Option<String>,
+    img: Option<String>,
+    url: Option<String>,
+    toc: Option<bool>,
+}
+
+impl JsonChapter {
+    /// Where the chapter starts
+    pub fn start(&self) -> Duration {
+        seconds(self.start_time)
+    }
+    /// Where the chapter ends, if the file says
+    pub fn end(&self) -> Option<Duration> {
+        self.end_time.map(seconds).filter(|end| *end > self.start())
+    }
+    /// The title of the chapter
+    pub fn title(&self) -> Option<&str> {
+        self.title
+            .as_deref()
+            .filter(|title| !title.trim().is_empty())
+    }
+    /// The URL of an image to show during the chapter
+    pub fn image(&self) -> Option<&str> {
+        self.img.as_deref().filter(|img| !img.trim().is_empty())
+    }
+    /// A web page that the chapter is about
+    pub fn url(&self) -> Option<&str> {
+        self.url.as_deref().filter(|url| !url.trim().is_empty())
+    }
+    /// Whether the chapter belongs in the table of contents.
+    ///
+    /// Chapters that don't are markers, such as an image change partway through a chapter, and
+    /// shouldn't be navigated to.
+    pub fn in_table_of_contents(&self) -> bool {
+        self.toc.unwrap_or(true)
+    }
+}
+
+/// Parse a JSON chapters file, sorting the chapters by when they start.
+///
+/// Chapters with a negative or otherwise unusable start time are left out.
+pub fn parse(json: &[u8]) -> Result<Vec<JsonChapter>, String> {
+    let document: Document =
+        serde_json::from_slice(json).map_err(|e| format!("Invalid chapters: {}", e))?;
+    let mut chapters: Vec<JsonChapter> = document
+        .chapters
+        .into_iter()
+        .filter(|chapter| chapter.start_time.is_finite() && chapter.start_time >= 0.0)
+        .collect();
+    chapters.sort_by_key(JsonChapter::start);
+    Ok(chapters)
+}
+
+fn seconds(seconds: f64) -> Duration {
+    Duration::try_from_secs_f64(seconds).unwrap_or_default()
+}
+
+/// A timestamp in show notes, like `12:34` or `1:02:03`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Timestamp {
+    range: Range<usize>,
+    position: Duration,
+}
+
+impl Timestamp {
+    /// Where the timestamp is in the show notes, in bytes
+    pub fn range(&self) -> Range<usize> {
+        self.range.clone()
+    }
+    /// Where in the episode the timestamp points to
+    pub fn position(&self) -> Duration {
+        self.position
+    }
+}
+
+/// A chapter that is listed in an episode's show notes, rather than published as a chapter
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShowNoteChapter {
+    start: Duration,
+    title: String,
+}
+
+impl ShowNoteChapter {
+    /// Where the chapter starts
+    pub fn start(&self) -> Duration {
+        self.start
+    }
+    /// The title of the chapter, which is the rest of the line that the timestamp was on
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+}
+
+/// Find every timestamp in show notes, which may be HTML.
+///
+/// Timestamps are `MM:SS` or `H:MM:SS`. Anything inside a tag or an existing link is left out,
+/// as are times of day like `10:30am`.
+pub fn find_timestamps(notes: &str) -> Vec<Timestamp> {
+    let bytes = notes.as_bytes();
+    let mut found = vec![];
+    let mut in_link = false;
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'<' {
+            let (tag, end) = tag_at(notes, index);
+            if tag == "a" {
+                in_link = true;
+            } else if tag == "/a" {
+                in_link = false;
+            }
+            index = end;
+            continue;
+        }
+        let starts_word = index == 0 || !is_part_of_word(bytes[index - 1]);
+        if !bytes[index].is_ascii_digit() || !starts_word {
+            index += 1;
+            continue;
+        }
+        let length = bytes[index..]
+            .iter()
+            .take_while(|byte| byte.is_ascii_digit() || **byte == b':')
+            .count();
+        let token = notes[index..index + length].trim_end_matches(':');
+        let end = index + token.len();
+        let ends_word = bytes
+            .get(end)
+            .is_none_or(|byte| !byte.is_ascii_alphanumeric());
+        if let Some(position) = parse_timestamp(token).filter(|_| ends_word && !in_link) {
+            found.push(Timestamp {
+                range: index..end,
+                position,
+            });
+        }
+        index += length;
+    }
+    found
+}
+
+/// Turn every timestamp in show notes into a link, so that following it can seek to that point.
+///
+/// `href` gives where the link for each position points to, such as a `sarcast:` URI that the
+/// renderer handles.
+pub fn link_timestamps(notes: &str, href: impl Fn(Duration) -> String) -> String {
+    let mut linked = String::with_capacity(notes.len());
+    let mut last = 0;
+    for timestamp in find_timestamps(notes) {
+        linked.push_str(&notes[last..timestamp.range.start]);
+        let _ = write!(
+            linked,
+            "<a href=\"{}\">{}</a>",
+            href(timestamp.position),
+            &notes[timestamp.range()]
+        );
+        last = timestamp.range.end;
+    }
+    linked.push_str(&notes[last..]);
+    linked
+}
+
+/// Build a list of chapters from the timestamps in show notes, which may be HTML.
+///
+/// Each line that starts or ends with a timestamp is a chapter, titled by the rest of the line.
+/// List markers, brackets and dashes around the timestamp are ignored. Notes with fewer than two
+/// such lines give no chapters, as a lone timestamp is more likely a mention than a list.
+pub fn show_note_chapters(notes: &str) -> Vec<ShowNoteChapter> {
+    let mut chapters: Vec<ShowNoteChapter> = plain_text(notes)
+        .lines()
+        .filter_map(chapter_from_line)
+        .collect();
+    chapters.sort_by_key(|chapter| chapter.start);
+    chapters.dedup_by_key(|chapter| chapter.start);
+    if chapters.len() < 2 {
+        return vec![];
+    }
+    chapters
+}
+
+fn chapter_from_line(line: &str) -> Option<ShowNoteChapter> {
+    let is_separator = |c: char| {
+        c.is_whitespace() || matches!(c, '-' | '–' | '—' | ':' | '|' | '(' | ')' | '[' | ']')
+    };
+    let timestamps = find_timestamps(line);
+    let (first, last) = (timestamps.first()?, timestamps.last()?);
+    let (timestamp, title) = if !has_words(&line[..first.range.start]) {
+        (first, &line[first.range.end..])
+    } else if !has_words(&line[last.range.end..]) {
+        (last, &line[..last.range.start])
+    } else {
+        return None;
+    };
+    let title = title.trim_matches(is_separator);
+    (!title.is_empty()).then(|| ShowNoteChapter {
+        start: timestamp.position,
+        title: title.to_owned(),
+    })
+}
+
+/// Whether `text` has anything in it other than punctuation and list numbering
+fn has_words(text: &str) -> bool {
+    text.chars().any(char::is_alphabetic)
+}
+
+/// Parse `MM:SS` or `H:MM:SS`
+fn parse_timestamp(token: &str) -> Option<Duration> {
+    let parts: Vec<&str> = token.split(':').collect();
+    let (first, rest) = parts.split_first()?;
+    let valid = match rest {
+        [seconds] => first.len() <= 3 && seconds.len() == 2,
+        [minutes, seconds] => first.len() <= 2 && minutes.len() == 2 && seconds.len() == 2,
+        _ => false,
+    };
+    if !valid || first.is_empty() {
+        return None;
+    }
+    let mut total = 0;
+    for (index, part) in parts.iter().enumerate() {
+        let value: u64 = part.parse().ok()?;
+        if index > 0 && value >= 60 {
+            return None;
+        }
+        total = total * 60 + value;
+    }
+    Some(Duration::from_secs(total))
+}
+
+/// Whether `byte` could be part of a word or number that a timestamp can't start inside of
+fn is_part_of_word(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || matches!(byte, b':' | b'.' | b'/' | b'_')
+}
+
+/// The lowercased name of the tag starting at `start`, with a `/` for closing tags, and where
+/// the tag ends
+fn tag_at(html: &str, start: usize) -> (String, usize) {
+    let (inner, end) = match html[start..].find('>') {
+        Some(offset) => (&html[start + 1..start + offset], start + offset + 1),
+        None => (&html[start + 1..], html.len()),
+    };
+    let name: String = inner
+        .trim_start()
+        .chars()
+        .enumerate()
+        .take_while(|(index, c)| c.is_ascii_alphanumeric() || (*index == 0 && *c == '/'))
+        .map(|(_, c)| c.to_ascii_lowercase())
+        .collect();
+    (name, end)
+}
+
+/// Strip the markup from show notes, keeping a line break wherever a block of text ends
+fn plain_text(notes: &str) -> String {
+    const BREAKS: &[&str] = &[
+        "br", "p", "li", "div", "ul", "ol", "tr", "h1", "h2", "h3", "h4",
+    ];
+    let mut text = String::with_capacity(notes.len());
+    let mut index = 0;
+    while let Some(offset) = notes[index..].find('<') {
+        text.push_str(&notes[index..index + offset]);
+        let (tag, end) = tag_at(notes, index + offset);
+        if BREAKS.contains(&tag.trim_start_matches('/')) {
+            text.push('\n');
+        }
+        index = end;
+    }
+    text.push_str(&notes[index..]);
+    [
+        ("&nbsp;", " "),
+        ("&ndash;", "–"),
+        ("&#8211;", "–"),
+        ("&mdash;", "—"),
+        ("&#8212;", "—"),
+        ("&lt;", "<"),
+        ("&gt;", ">"),
+        ("&quot;", "\""),
+        ("&#39;", "'"),
+        ("&amp;", "&"),
+    ]
+    .iter()
+    .fold(text, |text, (entity, decoded)| {
+        text.replace(entity, decoded)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub(crate) fn parse_json_chapters() {
+        let json = br#"{
+            "version": "1.2.0",
+            "chapters": [
+                {"startTime": 90.5, "title": "News", "img": "https://example.com/news.jpg"},
+                {"startTime": 0, "title": "Intro", "endTime": 90.5},
+                {"startTime": 120, "toc": false, "img": "https://example.com/map.png"},
+                {"startTime": -1, "title": "Broken"}
+            ]
+        }"#;
+        let chapters = parse(json).unwrap();
+        assert_eq!(chapters.len(), 3);
+        assert_eq!(chapters[0].title(), Some("Intro"));
+        assert_eq!(chapters[0].end(), Some(Duration::from_millis(90_500)));
+        assert_eq!(chapters[1].start(), Duration::from_millis(90_500));
+        assert_eq!(chapters[1].image(), Some("https://example.com/news.jpg"));
+        assert!(chapters[1].in_table_of_contents());
+        assert!(!chapters[2].in_table_of_contents());
+        assert_eq!(chapters[2].title(), None);
+
+        assert!(parse(b"{\"version\": \"1.2.0\"}").is_err());
+    }
+
+    #[test]
+    pub(crate) fn chapters_from_show_notes() {
+        let notes = "<p>Links: <a href=\"https://example.com/?t=1:00\">example.com</a></p>\
+            <ul><li>00:00 &ndash; Intro</li><li>[12:34] News</li>\
+            <li>1. 1:02:03 - Interview</li><li>Listener mail (1:30:00)</li></ul>\
+            <p>Recorded at 10:30am, call 555:1234</p>";
+        let chapters = show_note_chapters(notes);
+        let titles: Vec<&str> = chapters.iter().map(ShowNoteChapter::title).collect();
+        assert_eq!(titles, ["Intro", "News", "Interview", "Listener mail"]);
+        assert_eq!(chapters[1].start(), Duration::from_secs(12 * 60 + 34));
+        assert_eq!(chapters[3].start().as_secs(), 90 * 60);
+
+        assert!(show_note_chapters("We talk about it at 12:34.").is_empty());
+        let linked = link_timestamps("Skip to 12:34, not <a>1:00</a>.", |at| {
+            format!("#t={}", at.as_secs())
+        });
+        assert_eq!(
+            linked,
+            "Skip to <a href=\"#t=754\">12:34</a>, not <a>1:00</a>."
+        );
+    }
+}
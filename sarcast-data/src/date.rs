@@ -0,0 +1,281 @@
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, TimeZone};
+
+/// Time zone abbreviations that turn up in feeds, and their offsets from UTC in minutes.
+///
+/// `chrono` already understands the North American zones in RFC 2822 dates, but the fallback
+/// formats need all of them spelt out. Where an abbreviation is shared, the zone that podcast
+/// feeds mean by it is used, like India's for IST.
+const ZONES: &[(&str, i32)] = &[
+    ("UT", 0),
+    ("UTC", 0),
+    ("GMT", 0),
+    ("Z", 0),
+    ("EST", -300),
+    ("EDT", -240),
+    ("CST", -360),
+    ("CDT", -300),
+    ("MST", -420),
+    ("MDT", -360),
+    ("PST", -480),
+    ("PDT", -420),
+    ("AKST", -540),
+    ("AKDT", -480),
+    ("HST", -600),
+    ("BST", 60),
+    ("IST", 330),
+    ("WET", 0),
+    ("WEST", 60),
+    ("CET", 60),
+    ("CEST", 120),
+    ("EET", 120),
+    ("EEST", 180),
+    ("MSK", 180),
+    ("JST", 540),
+    ("KST", 540),
+    ("AWST", 480),
+    ("ACST", 570),
+    ("AEST", 600),
+    ("AEDT", 660),
+    ("NZST", 720),
+    ("NZDT", 780),
+];
+
+/// Formats with an explicit offset, tried after any time zone name has been replaced.
+///
+/// Two digit years are tried first, as `%Y` will happily read "22" as the year 22.
+const ZONED_FORMATS: &[&str] = &[
+    "%d %b %y %H:%M:%S %z",
+    "%d %b %y %H:%M %z",
+    "%d %b %Y %H:%M:%S %z",
+    "%d %b %Y %H:%M %z",
+    "%d %B %Y %H:%M:%S %z",
+    "%d %B %Y %H:%M %z",
+    "%b %d %Y %H:%M:%S %z",
+    "%B %d %Y %H:%M:%S %z",
+    "%Y-%m-%d %H:%M:%S %z",
+    "%Y-%m-%d %H:%M:%S%z",
+    "%Y-%m-%dT%H:%M:%S%z",
+    "%Y-%m-%dT%H:%M:%S%.f%z",
+    "%Y-%m-%dT%H:%M%z",
+];
+
+/// Formats without an offset, which are assumed to be in UTC.
+const NAIVE_FORMATS: &[&str] = &[
+    "%d %b %y %H:%M:%S",
+    "%d %b %Y %H:%M:%S",
+    "%d %b %Y %H:%M",
+    "%d %B %Y %H:%M:%S",
+    "%b %d %Y %H:%M:%S",
+    "%Y-%m-%d %H:%M:%S",
+    "%Y-%m-%dT%H:%M:%S",
+    "%Y-%m-%dT%H:%M:%S%.f",
+    "%Y-%m-%dT%H:%M",
+];
+
+/// Formats without a time, which are assumed to be midnight UTC.
+const DATE_FORMATS: &[&str] = &[
+    "%Y-%m-%d", "%d %b %y", "%d %b %Y", "%d %B %Y", "%b %d %Y", "%B %d %Y", "%Y/%m/%d",
+];
+
+/// Parse a date from a feed.
+///
+/// Strict RFC 2822 and RFC 3339 dates are tried first. If neither match, the date is cleaned up by
+/// removing any day of the week, replacing named time zones with their offsets, and a number of
+/// common variations (two digit years, missing seconds, missing time zones) are tried. Dates
+/// without a time zone are assumed to be in UTC.
+pub fn parse(date: &str) -> Option<DateTime<FixedOffset>> {
+    let date = date.trim();
+    if date.is_empty() {
+        return None;
+    }
+    if let Ok(parsed) = DateTime::parse_from_rfc2822(date) {
+        return Some(parsed);
+    }
+    if let Ok(parsed) = DateTime::parse_from_rfc3339(date) {
+        return Some(parsed);
+    }
+
+    let normalized = normalize(date);
+    let utc = FixedOffset::east_opt(0)?;
+    ZONED_FORMATS
+        .iter()
+        .find_map(|format| DateTime::parse_from_str(&normalized, format).ok())
+        .or_else(|| {
+            NAIVE_FORMATS.iter().find_map(|format| {
+                NaiveDateTime::parse_from_str(&normalized, format)
+                    .ok()
+                    .and_then(|naive| utc.from_local_datetime(&naive).single())
+            })
+        })
+        .or_else(|| {
+            DATE_FORMATS.iter().find_map(|format| {
+                NaiveDate::parse_from_str(&normalized, format)
+                    .ok()
+                    .and_then(|day| day.and_hms_opt(0, 0, 0))
+                    .and_then(|naive| utc.from_local_datetime(&naive).single())
+            })
+        })
+}
+
+/// Parse a date from a feed into seconds since the Unix epoch
+pub fn parse_timestamp(date: &str) -> Option<i64> {
+    parse(date).map(|date| date.timestamp())
+}
+
+/// Parse a duration from a feed, like `<itunes:duration>`, into milliseconds.
+///
+/// Durations may be given as `HH:MM:SS`, `MM:SS` or a number of seconds, and the seconds may
+/// have a fraction.
+pub fn parse_duration(duration: &str) -> Option<i32> {
+    let parts: Vec<&str> = duration.trim().split(':').map(str::trim).collect();
+    if parts.len() > 3 || parts.iter().any(|part| part.is_empty()) {
+        return None;
+    }
+    let (seconds, minutes_and_hours) = parts.split_last()?;
+    let seconds: f64 = seconds
+        .parse()
+        .ok()
+        .filter(|s: &f64| s.is_finite() && *s >= 0.0)?;
+    let mut total = 0_u32;
+    for part in minutes_and_hours {
+        total = total.checked_mul(60)?.checked_add(part.parse().ok()?)?;
+    }
+    let milliseconds = (f64::from(total) * 60.0 + seconds) * 1000.0;
+    if milliseconds > f64::from(i32::MAX) {
+        return None;
+    }
+    #[allow(clippy::cast_possible_truncation)]
+    Some(milliseconds.round() as i32)
+}
+
+/// Strip the day of the week, stray punctuation and named time zones from a date.
+fn normalize(date: &str) -> String {
+    let mut words: Vec<String> = date
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|word| !word.is_empty())
+        .map(str::to_owned)
+        .collect();
+
+    // Days of the week are redundant (and often wrong), so drop them entirely
+    if matches!(words.first(), Some(word) if word.len() >= 3 && is_weekday(word)) {
+        let _ = words.remove(0);
+    }
+
+    for word in &mut words {
+        // Ordinal suffixes, like "1st" or "22nd"
+        if let Some(day) = strip_ordinal(word) {
+            *word = day;
+            continue;
+        }
+        // "Sept" isn't understood by chrono
+        if word.eq_ignore_ascii_case("sept") {
+            *word = "Sep".into();
+            continue;
+        }
+        if let Some(offset) = zone_offset(word) {
+            *word = offset;
+        }
+    }
+
+    words.join(" ")
+}
+
+fn is_weekday(word: &str) -> bool {
+    const DAYS: &[&str] = &["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+    let lower = word.to_ascii_lowercase();
+    DAYS.iter().any(|day| lower.starts_with(day))
+        && lower.chars().all(|c| c.is_ascii_alphabetic() || c == '.')
+}
+
+fn strip_ordinal(word: &str) -> Option<String> {
+    let lower = word.to_ascii_lowercase();
+    ["st", "nd", "rd", "th"].iter().find_map(|suffix| {
+        let day = lower.strip_suffix(suffix)?;
+        (!day.is_empty() && day.len() <= 2 && day.chars().all(|c| c.is_ascii_digit()))
+            .then(|| day.to_owned())
+    })
+}
+
+fn zone_offset(word: &str) -> Option<String> {
+    let upper = word.to_ascii_uppercase();
+    // Zones may be written with a trailing offset, like "GMT+2" or "UTC-05:00"
+    let (name, extra) = match upper.find(['+', '-']) {
+        Some(split) if split > 0 => upper.split_at(split),
+        _ => (upper.as_str(), ""),
+    };
+    let mut minutes = ZONES
+        .iter()
+        .find(|(zone, _)| *zone == name)
+        .map(|(_, minutes)| *minutes)?;
+    if !extra.is_empty() {
+        let sign = if extra.starts_with('-') { -1 } else { 1 };
+        let digits: String = extra[1..].chars().filter(char::is_ascii_digit).collect();
+        let (extra_hours, extra_minutes) = match digits.len() {
+            1 | 2 => (digits.parse::<i32>().ok()?, 0),
+            3 | 4 => {
+                let (h, m) = digits.split_at(digits.len() - 2);
+                (h.parse::<i32>().ok()?, m.parse::<i32>().ok()?)
+            }
+            _ => return None,
+        };
+        minutes += sign * (extra_hours * 60 + extra_minutes);
+    }
+    let sign = if minutes < 0 { '-' } else { '+' };
+    let minutes = minutes.abs();
+    Some(format!("{}{:02}{:02}", sign, minutes / 60, minutes % 60))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub(crate) fn parse_feed_dates() {
+        let cases = [
+            // Strict RFC 2822
+            ("Thu, 13 Oct 2022 21:30:00 +0000", 1_665_696_600),
+            // RFC 3339
+            ("2022-10-13T21:30:00Z", 1_665_696_600),
+            ("2022-10-13T14:30:00-07:00", 1_665_696_600),
+            // Missing the day of the week
+            ("13 Oct 2022 21:30:00 GMT", 1_665_696_600),
+            // The wrong day of the week
+            ("Mon, 13 Oct 2022 21:30:00 GMT", 1_665_696_600),
+            // Named time zones
+            ("Thu, 13 Oct 2022 14:30:00 PDT", 1_665_696_600),
+            ("Fri, 14 Oct 2022 08:30:00 AEDT", 1_665_696_600),
+            ("Thu, 13 Oct 2022 23:30:00 GMT+2", 1_665_696_600),
+            // Zones that are not a whole number of hours from UTC
+            ("Fri, 14 Oct 2022 03:00:00 IST", 1_665_696_600),
+            ("Fri, 14 Oct 2022 07:00:00 ACST", 1_665_696_600),
+            // Two digit years
+            ("Thu, 13 Oct 22 21:30:00 +0000", 1_665_696_600),
+            // No seconds
+            ("Thursday, 13 October 2022 21:30 UTC", 1_665_696_600),
+            // No time zone
+            ("2022-10-13 21:30:00", 1_665_696_600),
+            // No time
+            ("2022-10-13", 1_665_619_200),
+            ("October 13th, 2022", 1_665_619_200),
+            // Past 2038
+            ("Sat, 01 Jan 2050 00:00:00 +0000", 2_524_608_000),
+        ];
+        for (date, expected) in cases {
+            assert_eq!(parse_timestamp(date), Some(expected), "{}", date);
+        }
+        assert_eq!(parse_timestamp(""), None);
+        assert_eq!(parse_timestamp("last tuesday"), None);
+    }
+
+    #[test]
+    pub(crate) fn parse_feed_durations() {
+        assert_eq!(parse_duration("01:02:03"), Some(3_723_000));
+        assert_eq!(parse_duration("62:03"), Some(3_723_000));
+        assert_eq!(parse_duration("3723"), Some(3_723_000));
+        assert_eq!(parse_duration(" 12.5 "), Some(12_500));
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("1:2:3:4"), None);
+        assert_eq!(parse_duration("an hour"), None);
+        assert_eq!(parse_duration("-5"), None);
+    }
+}
@@ -0,0 +1,280 @@
+use diesel::prelude::*;
+use diesel::upsert::excluded;
+
+use crate::models::{Episode, NewEnclosure, NewEpisode, NewPodcast, Podcast, Source};
+use crate::schema::{downloads, enclosures, episodes};
+
+/// The result of indexing a feed
+#[derive(Debug, Clone)]
+pub struct Indexed {
+    /// The podcast that the feed describes
+    pub podcast: Podcast,
+    /// The episodes that were newly found to be missing from the feed
+    pub removed: Vec<Episode>,
+}
+
+/// Store the podcast and episodes from `channel`, which was read from `source`.
+///
+/// Episodes that are already known are updated with what the feed now says about them, keeping
+/// where they have been played and downloaded to. An episode whose title has changed is followed
+/// by its `guid`. Known episodes that are no longer in the feed are marked as removed upstream.
+pub fn index(
+    con: &mut SqliteConnection,
+    source: &Source,
+    channel: &rss::Channel,
+) -> Result<Indexed, String> {
+    let new_podcast = NewPodcast::try_from((channel, source))?;
+    con.transaction(|con| {
+        let podcast = new_podcast.upsert(con)?;
+        // Items that can't be parsed can't be stored either, so skip over them.
+        let seen: Vec<NewEpisode> = channel
+            .items()
+            .iter()
+            .filter_map(|item| NewEpisode::try_from((item, &podcast)).ok())
+            .collect();
+        for episode in &seen {
+            if let Some(guid) = episode.guid() {
+                rename(con, podcast.id(), guid, episode.title())?;
+            }
+            upsert_episode(con, episode)?;
+        }
+        let offered: Vec<NewEnclosure> = channel
+            .items()
+            .iter()
+            .flat_map(|item| NewEnclosure::from_rss(item, podcast.id()))
+            .filter(|enclosure| {
+                seen.iter()
+                    .any(|episode| episode.title() == enclosure.episode_title())
+            })
+            .collect();
+        for enclosure in &offered {
+            let _ = diesel::insert_into(enclosures::table)
+                .values(enclosure)
+                .on_conflict((
+                    enclosures::episode_title,
+                    enclosures::podcast_id,
+                    enclosures::uri,
+                ))
+                .do_update()
+                .set((
+                    enclosures::mime_type.eq(excluded(enclosures::mime_type)),
+                    enclosures::length.eq(excluded(enclosures::length)),
+                    enclosures::bitrate.eq(excluded(enclosures::bitrate)),
+                    enclosures::codecs.eq(excluded(enclosures::codecs)),
+                    enclosures::title.eq(excluded(enclosures::title)),
+                    enclosures::is_default.eq(excluded(enclosures::is_default)),
+                    enclosures::integrity.eq(excluded(enclosures::integrity)),
+                ))
+                .execute(con)?;
+        }
+        let removed = Episode::mark_removed(con, podcast.id(), &seen)?;
+        Ok(Indexed { podcast, removed })
+    })
+    .map_err(|e: diesel::result::Error| format!("{}", e))
+}
+
+/// Insert `episode`, or update what the feed says about it if it is already known.
+///
+/// Where it has been played and downloaded to are kept. The duration is only filled in if it
+/// isn't known, as the one measured from the media is more accurate than the feed's.
+fn upsert_episode(con: &mut SqliteConnection, episode: &NewEpisode) -> QueryResult<()> {
+    let _ = diesel::insert_into(episodes::table)
+        .values(episode)
+        .on_conflict((episodes::title, episodes::podcast_id))
+        .do_update()
+        .set((
+            episodes::uri.eq(excluded(episodes::uri)),
+            episodes::description.eq(excluded(episodes::description)),
+            episodes::length.eq(excluded(episodes::length)),
+            episodes::guid.eq(excluded(episodes::guid)),
+            episodes::epoch.eq(excluded(episodes::epoch)),
+            episodes::mime_type.eq(excluded(episodes::mime_type)),
+            episodes::file_extension.eq(excluded(episodes::file_extension)),
+            episodes::season.eq(excluded(episodes::season)),
+            episodes::episode_number.eq(excluded(episodes::episode_number)),
+            episodes::episode_type.eq(excluded(episodes::episode_type)),
+            episodes::chapters_uri.eq(excluded(episodes::chapters_uri)),
+        ))
+        .execute(con)?;
+    if episode.duration().is_some() {
+        let _ = diesel::update(
+            episodes::table
+                .find((episode.title(), episode.podcast_id()))
+                .filter(episodes::duration.is_null()),
+        )
+        .set(episodes::duration.eq(episode.duration()))
+        .execute(con)?;
+    }
+    Ok(())
+}
+
+/// Follow the episode with `guid` to its new `title`, along with its download and enclosures, if
+/// the feed has retitled it.
+///
+/// Nothing is renamed if the `guid` isn't unique, or an episode already has the new title.
+fn rename(con: &mut SqliteConnection, podcast_id: i32, guid: &str, title: &str) -> QueryResult<()> {
+    let titles: Vec<String> = episodes::table
+        .filter(episodes::podcast_id.eq(podcast_id))
+        .filter(episodes::guid.eq(guid))
+        .select(episodes::title)
+        .load(con)?;
+    let [old] = titles.as_slice() else {
+        return Ok(());
+    };
+    if old == title || Episode::find(con, podcast_id, title)?.is_some() {
+        return Ok(());
+    }
+    let _ = diesel::update(episodes::table.find((old, podcast_id)))
+        .set(episodes::title.eq(title))
+        .execute(con)?;
+    let _ = diesel::update(
+        enclosures::table
+            .filter(enclosures::podcast_id.eq(podcast_id))
+            .filter(enclosures::episode_title.eq(old)),
+    )
+    .set(enclosures::episode_title.eq(title))
+    .execute(con)?;
+    let _ = diesel::update(
+        downloads::table
+            .filter(downloads::podcast_id.eq(podcast_id))
+            .filter(downloads::episode_title.eq(old)),
+    )
+    .set(downloads::episode_title.eq(title))
+    .execute(con)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::Removed;
+    use std::fs::File;
+    use std::io::BufReader;
+
+    #[test]
+    pub(crate) fn removed_episodes() -> Result<(), Box<dyn std::error::Error>> {
+        let mut con = SqliteConnection::establish(":memory:")?;
+        crate::run_migration_on(&mut con)?;
+        let source = Source::get_or_create(&mut con, "https://atp.fm/rss")?;
+
+        let file = File::open("test-data/feeds/atp.xml")?;
+        let mut channel = rss::Channel::read_from(BufReader::new(file))?;
+        let first = index(&mut con, &source, &channel)?;
+        assert!(first.removed.is_empty());
+        let all = Episode::for_podcast(&mut con, first.podcast.id(), Removed::Include)?;
+
+        let pulled = channel.items()[0].title().unwrap().to_owned();
+        channel.set_items(channel.items()[1..].to_owned());
+        let second = index(&mut con, &source, &channel)?;
+        assert_eq!(second.podcast.id(), first.podcast.id());
+        assert_eq!(second.removed.len(), 1);
+        assert_eq!(second.removed[0].title(), pulled);
+
+        let podcast_id = first.podcast.id();
+        let removed = Episode::for_podcast(&mut con, podcast_id, Removed::Only)?;
+        assert_eq!(removed.len(), 1);
+        let remaining = Episode::for_podcast(&mut con, podcast_id, Removed::Exclude)?;
+        assert_eq!(remaining.len(), all.len() - 1);
+        Ok(())
+    }
+
+    #[test]
+    pub(crate) fn broken_feeds_keep_episodes() -> Result<(), Box<dyn std::error::Error>> {
+        let mut con = SqliteConnection::establish(":memory:")?;
+        crate::run_migration_on(&mut con)?;
+        let source = Source::get_or_create(&mut con, "https://atp.fm/rss")?;
+
+        let file = File::open("test-data/feeds/atp.xml")?;
+        let mut channel = rss::Channel::read_from(BufReader::new(file))?;
+        let podcast_id = index(&mut con, &source, &channel)?.podcast.id();
+        let all = channel.items().to_owned();
+
+        // A feed that is served empty, or with only a few of its episodes, is left alone
+        channel.set_items(vec![]);
+        assert!(index(&mut con, &source, &channel)?.removed.is_empty());
+        channel.set_items(all[..all.len() / 3].to_owned());
+        assert!(index(&mut con, &source, &channel)?.removed.is_empty());
+        let removed = Episode::for_podcast(&mut con, podcast_id, Removed::Only)?;
+        assert!(removed.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    pub(crate) fn large_back_catalogue() -> Result<(), Box<dyn std::error::Error>> {
+        let mut con = SqliteConnection::establish(":memory:")?;
+        crate::run_migration_on(&mut con)?;
+        let source = Source::get_or_create(&mut con, "https://example.com/feed")?;
+
+        let item = |number: usize| {
+            rss::ItemBuilder::default()
+                .title(format!("Episode {}", number))
+                .enclosure(
+                    rss::EnclosureBuilder::default()
+                        .url(format!("https://example.com/{}.mp3", number))
+                        .mime_type("audio/mpeg".to_owned())
+                        .build(),
+                )
+                .build()
+        };
+        let mut channel = rss::ChannelBuilder::default()
+            .title("Example")
+            .link("https://example.com")
+            .items((0..2500).map(item).collect::<Vec<_>>())
+            .build();
+        let podcast_id = index(&mut con, &source, &channel)?.podcast.id();
+
+        channel.set_items((0..2400).map(item).collect::<Vec<_>>());
+        let indexed = index(&mut con, &source, &channel)?;
+        assert_eq!(indexed.removed.len(), 100);
+        let remaining = Episode::for_podcast(&mut con, podcast_id, Removed::Exclude)?;
+        assert_eq!(remaining.len(), 2400);
+        Ok(())
+    }
+
+    #[test]
+    pub(crate) fn refresh_known_episodes() -> Result<(), Box<dyn std::error::Error>> {
+        let mut con = SqliteConnection::establish(":memory:")?;
+        crate::run_migration_on(&mut con)?;
+        let source = Source::get_or_create(&mut con, "https://atp.fm/rss")?;
+
+        let file = File::open("test-data/feeds/atp.xml")?;
+        let mut channel = rss::Channel::read_from(BufReader::new(file))?;
+        let podcast_id = index(&mut con, &source, &channel)?.podcast.id();
+        let original = channel.items()[0].title().unwrap().to_owned();
+        Episode::set_played(&mut con, podcast_id, &original, Some(1))?;
+        Episode::set_play_position(&mut con, podcast_id, &original, 60_000)?;
+        Episode::set_local_uri(&mut con, podcast_id, &original, Some("/tmp/episode.mp3"))?;
+        // Columns that were added after an episode was first stored are filled in, but the
+        // duration measured from the media is kept
+        let second = channel.items()[1].title().unwrap().to_owned();
+        Episode::set_duration(&mut con, podcast_id, &second, None)?;
+        Episode::set_duration(&mut con, podcast_id, &original, Some(5))?;
+
+        // The feed fixes the title and moves the media
+        let mut items = channel.items().to_owned();
+        items[0].set_title("Retitled".to_owned());
+        let mut enclosure = items[0].enclosure().unwrap().clone();
+        enclosure.set_url("https://cdn.example.com/episode.mp3");
+        enclosure.set_length("123456");
+        items[0].set_enclosure(enclosure);
+        channel.set_items(items);
+        let indexed = index(&mut con, &source, &channel)?;
+        assert!(indexed.removed.is_empty());
+
+        assert!(Episode::find(&mut con, podcast_id, &original)?.is_none());
+        let episode = Episode::find(&mut con, podcast_id, "Retitled")?.unwrap();
+        assert_eq!(episode.uri(), Some("https://cdn.example.com/episode.mp3"));
+        assert_eq!(episode.length(), Some(123_456));
+        assert_eq!(episode.play_position(), 60_000);
+        assert_eq!(episode.played(), Some(1));
+        assert_eq!(episode.local_uri(), Some("/tmp/episode.mp3"));
+        assert_eq!(episode.duration(), Some(5));
+        let second = Episode::find(&mut con, podcast_id, &second)?.unwrap();
+        assert!(second.duration().is_some());
+        let enclosures = crate::models::Enclosure::for_episode(&mut con, &episode)?;
+        assert!(enclosures
+            .iter()
+            .any(|enclosure| enclosure.uri() == "https://cdn.example.com/episode.mp3"));
+        Ok(())
+    }
+}
@@ -8,17 +8,28 @@
 #![deny(unused)]
 #![deny(clippy::pedantic)]
 
-use diesel::migration::MigrationSource;
 use diesel::prelude::*;
 use diesel::{r2d2, r2d2::ConnectionManager};
-use diesel_migrations::{embed_migrations, EmbeddedMigrations};
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
 use once_cell::sync::Lazy;
 use std::path::PathBuf;
 
+/// Reading the chapters that feeds publish alongside episodes, or list in their show notes
+pub mod chapters;
+/// Lenient parsing of the dates and durations found in feeds
+pub mod date;
+/// Storing parsed feeds in the database
+pub mod feed;
+/// Working out what kind of media an episode is
+pub mod media;
 /// The database models used for sarcast
 pub mod models;
+/// Naming the files that episodes are downloaded to
+pub mod naming;
 #[allow(missing_docs)]
 pub mod schema;
+/// How much space downloaded episodes take up
+pub mod storage;
 
 type Pool = r2d2::Pool<ConnectionManager<SqliteConnection>>;
 
@@ -50,9 +61,11 @@ fn init_pool(db_path: &str) -> Pool {
 }
 
 fn run_migration_on(connection: &mut SqliteConnection) -> Result<(), String> {
-    for migration in MIGRATIONS.migrations().map_err(|e| format!("{}", e))? {
-        migration.run(connection).map_err(|e| format!("{}", e))?;
-    }
+    // Only run the migrations that haven't been applied yet, as later migrations alter tables
+    // that already exist.
+    let _ = connection
+        .run_pending_migrations(MIGRATIONS)
+        .map_err(|e| format!("{}", e))?;
     Ok(())
 }
 
@@ -61,11 +74,18 @@ pub fn truncate_db() -> Result<(), String> {
     use diesel::connection::SimpleConnection;
     let db = connection();
     let mut con = db.get().map_err(|e| format!("{}", e))?;
-    con.batch_execute("DELETE FROM episodes")
-        .map_err(|e| format!("{}", e))?;
-    con.batch_execute("DELETE FROM shows")
-        .map_err(|e| format!("{}", e))?;
-    con.batch_execute("DELETE FROM source")
-        .map_err(|e| format!("{}", e))?;
+    // Tables that refer to others are cleared before the tables they refer to
+    for table in [
+        "downloads",
+        "enclosures",
+        "download_rules",
+        "credentials",
+        "episodes",
+        "podcasts",
+        "source",
+    ] {
+        con.batch_execute(&format!("DELETE FROM {}", table))
+            .map_err(|e| format!("{}", e))?;
+    }
     Ok(())
 }
@@ -0,0 +1,185 @@
+/// Whether an episode is audio or video
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaKind {
+    /// An audio-only episode
+    Audio,
+    /// A video episode
+    Video,
+    /// Neither the MIME type nor the file extension say what the episode is
+    Unknown,
+}
+
+/// File extensions, the MIME type they are usually served as, and the kind of media they hold.
+///
+/// Where a MIME type has more than one extension, the first is the one that is used for it.
+const KNOWN_TYPES: &[(&str, &str, MediaKind)] = &[
+    ("mp3", "audio/mpeg", MediaKind::Audio),
+    ("m4a", "audio/mp4", MediaKind::Audio),
+    ("m4b", "audio/mp4", MediaKind::Audio),
+    ("aac", "audio/aac", MediaKind::Audio),
+    ("ogg", "audio/ogg", MediaKind::Audio),
+    ("oga", "audio/ogg", MediaKind::Audio),
+    ("opus", "audio/opus", MediaKind::Audio),
+    ("flac", "audio/flac", MediaKind::Audio),
+    ("wav", "audio/wav", MediaKind::Audio),
+    ("mp4", "video/mp4", MediaKind::Video),
+    ("m4v", "video/x-m4v", MediaKind::Video),
+    ("mov", "video/quicktime", MediaKind::Video),
+    ("webm", "video/webm", MediaKind::Video),
+    ("mkv", "video/x-matroska", MediaKind::Video),
+];
+
+/// Non-standard MIME types that feeds use, and the standard type that they mean
+const MIME_ALIASES: &[(&str, &str)] = &[
+    ("audio/mp3", "audio/mpeg"),
+    ("audio/mpeg3", "audio/mpeg"),
+    ("audio/x-mp3", "audio/mpeg"),
+    ("audio/x-mpeg", "audio/mpeg"),
+    ("audio/x-m4a", "audio/mp4"),
+    ("audio/m4a", "audio/mp4"),
+    ("audio/x-m4b", "audio/mp4"),
+    ("audio/x-aac", "audio/aac"),
+    ("audio/aacp", "audio/aac"),
+    ("audio/x-wav", "audio/wav"),
+    ("audio/x-flac", "audio/flac"),
+    ("video/x-mp4", "video/mp4"),
+];
+
+/// Clean up a MIME type from a feed.
+///
+/// Parameters (like `; charset=...`) are removed, the type is lowercased, and common
+/// non-standard types are replaced with their standard equivalent.
+pub fn normalize_mime_type(mime_type: &str) -> Option<String> {
+    let mime_type = mime_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    if !mime_type.contains('/') {
+        return None;
+    }
+    Some(
+        MIME_ALIASES
+            .iter()
+            .find(|(alias, _)| *alias == mime_type)
+            .map_or(mime_type, |(_, standard)| (*standard).to_owned()),
+    )
+}
+
+/// Get the file extension from the last segment of the path of a URI, ignoring any query or
+/// fragment.
+///
+/// Only the extensions of audio and video files are returned, so that host names and links to
+/// web pages (like `.html` or `.php`) aren't mistaken for the type of the media.
+pub fn extension_from_uri(uri: &str) -> Option<String> {
+    let uri = uri.split(['?', '#']).next().unwrap_or_default();
+    // Skip the scheme and host, which have no path of their own
+    let path = match uri.split_once("://") {
+        Some((_, rest)) => rest.split_once('/').map_or("", |(_, path)| path),
+        None => uri,
+    };
+    let file_name = path.rsplit('/').next().unwrap_or_default();
+    let (stem, extension) = file_name.rsplit_once('.')?;
+    let extension = extension.to_ascii_lowercase();
+    if stem.is_empty() || !KNOWN_TYPES.iter().any(|(known, _, _)| *known == extension) {
+        return None;
+    }
+    Some(extension)
+}
+
+/// Whether media with this MIME type or file extension is in an MP4 container, like `.m4a` and
+/// `.m4b` audio and `.mp4` video
+pub fn is_mp4(mime_type: Option<&str>, extension: Option<&str>) -> bool {
+    const MP4_EXTENSIONS: &[&str] = &["m4a", "m4b", "mp4", "m4v", "mov"];
+    let by_mime = mime_type
+        .and_then(normalize_mime_type)
+        .and_then(|mime_type| extension_for_mime_type(&mime_type));
+    by_mime
+        .into_iter()
+        .chain(extension)
+        .any(|extension| MP4_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str()))
+}
+
+/// Get the usual file extension for a MIME type
+pub fn extension_for_mime_type(mime_type: &str) -> Option<&'static str> {
+    let mime_type = normalize_mime_type(mime_type)?;
+    KNOWN_TYPES
+        .iter()
+        .find(|(_, known, _)| *known == mime_type)
+        .map(|(extension, _, _)| *extension)
+}
+
+impl MediaKind {
+    /// Work out the kind of media from its MIME type, falling back to its file extension
+    pub fn classify(mime_type: Option<&str>, extension: Option<&str>) -> MediaKind {
+        let from_mime =
+            mime_type
+                .and_then(normalize_mime_type)
+                .and_then(|mime_type| match mime_type.split('/').next() {
+                    Some("audio") => Some(MediaKind::Audio),
+                    Some("video") => Some(MediaKind::Video),
+                    _ => None,
+                });
+        let from_extension = || {
+            let extension = extension?.to_ascii_lowercase();
+            KNOWN_TYPES
+                .iter()
+                .find(|(known, _, _)| *known == extension)
+                .map(|(_, _, kind)| *kind)
+        };
+        from_mime
+            .or_else(from_extension)
+            .unwrap_or(MediaKind::Unknown)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub(crate) fn classify_enclosures() {
+        assert_eq!(
+            extension_from_uri("https://cdn.example.com/shows/ep1.MP3?source=rss#t=10"),
+            Some("mp3".into())
+        );
+        assert_eq!(
+            extension_from_uri("https://example.com/download/1234"),
+            None
+        );
+        assert_eq!(extension_from_uri("https://example.com/.hidden"), None);
+        assert_eq!(extension_from_uri("https://example.com"), None);
+        assert_eq!(extension_from_uri("https://example.com/"), None);
+        assert_eq!(extension_from_uri("https://example.com/ep1.mp3/"), None);
+        assert_eq!(
+            extension_from_uri("https://example.com/episodes/ep1.html"),
+            None
+        );
+        assert_eq!(
+            extension_from_uri("https://example.com/play.php?file=ep1.mp3"),
+            None
+        );
+        assert_eq!(
+            extension_from_uri("https://cdn.example.com/v2.1/ep1.m4a"),
+            Some("m4a".into())
+        );
+        assert_eq!(extension_from_uri("ep1.opus"), Some("opus".into()));
+        assert_eq!(
+            normalize_mime_type("Audio/X-M4A; charset=binary"),
+            Some("audio/mp4".into())
+        );
+        assert_eq!(extension_for_mime_type("audio/x-m4a"), Some("m4a"));
+
+        assert_eq!(
+            MediaKind::classify(Some("audio/mpeg"), Some("mp4")),
+            MediaKind::Audio
+        );
+        assert_eq!(MediaKind::classify(None, Some("m4v")), MediaKind::Video);
+        assert_eq!(
+            MediaKind::classify(Some("application/octet-stream"), Some("mp3")),
+            MediaKind::Audio
+        );
+        assert_eq!(MediaKind::classify(None, None), MediaKind::Unknown);
+    }
+}
@@ -0,0 +1,471 @@
+use super::{Enclosure, Episode};
+use crate::schema::downloads;
+use diesel::prelude::*;
+
+/// The number of times a download is attempted before it is given up on
+pub const MAX_DOWNLOAD_ATTEMPTS: i32 = 5;
+
+/// The delay before retrying a download that has failed once.
+const RETRY_BASE_SECONDS: i64 = 30;
+/// The longest that a failed download will wait before it is retried.
+const RETRY_MAX_SECONDS: i64 = 60 * 60;
+/// Enclosure lengths shorter than this are assumed to be placeholders, like `0` or `1`.
+const MIN_PLAUSIBLE_LENGTH: i64 = 1024;
+
+/// Where a download is up to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DownloadState {
+    /// Waiting for a free slot, or for its next retry
+    Queued,
+    /// Currently being downloaded
+    Downloading,
+    /// Downloaded to its final path
+    Completed,
+    /// Failed too many times to be retried automatically
+    Failed,
+    /// Held back because there isn't room for it within the storage quotas, until some is freed
+    NoRoom,
+}
+
+impl DownloadState {
+    /// The representation of this state in the database
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DownloadState::Queued => "queued",
+            DownloadState::Downloading => "downloading",
+            DownloadState::Completed => "completed",
+            DownloadState::Failed => "failed",
+            DownloadState::NoRoom => "no_room",
+        }
+    }
+}
+
+impl std::str::FromStr for DownloadState {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "queued" => Ok(DownloadState::Queued),
+            "downloading" => Ok(DownloadState::Downloading),
+            "completed" => Ok(DownloadState::Completed),
+            "failed" => Ok(DownloadState::Failed),
+            "no_room" => Ok(DownloadState::NoRoom),
+            _ => Err(format!("Unknown download state {}", s)),
+        }
+    }
+}
+
+#[derive(Queryable, Identifiable, AsChangeset, PartialEq)]
+#[diesel(table_name = downloads)]
+#[diesel(treat_none_as_null = true)]
+#[derive(Debug, Clone)]
+/// Diesel Model of the downloads table.
+pub struct Download {
+    id: i32,
+    episode_title: String,
+    podcast_id: i32,
+    uri: String,
+    path: String,
+    state: String,
+    downloaded_bytes: i64,
+    total_bytes: Option<i64>,
+    attempts: i32,
+    next_attempt: Option<chrono::NaiveDateTime>,
+    last_error: Option<String>,
+    created: chrono::NaiveDateTime,
+    expected_bytes: Option<i64>,
+    integrity: Option<String>,
+    verified: bool,
+    manual: bool,
+}
+
+impl Download {
+    /// The row ID of this download
+    pub fn id(&self) -> i32 {
+        self.id
+    }
+    /// The title of the episode being downloaded
+    pub fn episode_title(&self) -> &str {
+        &self.episode_title
+    }
+    /// The ID of the show that the episode is from
+    pub fn podcast_id(&self) -> i32 {
+        self.podcast_id
+    }
+    /// The URI that the episode is downloaded from
+    pub fn uri(&self) -> &str {
+        &self.uri
+    }
+    /// The path that the episode is saved to once it is complete
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+    /// The path that the episode is saved to while it is being downloaded
+    pub fn part_path(&self) -> String {
+        format!("{}.part", self.path)
+    }
+    /// Where this download is up to
+    pub fn state(&self) -> DownloadState {
+        self.state.parse().unwrap_or(DownloadState::Queued)
+    }
+    /// The number of bytes that have been saved so far
+    pub fn downloaded_bytes(&self) -> i64 {
+        self.downloaded_bytes
+    }
+    /// The total size of the download in bytes, if it is known
+    pub fn total_bytes(&self) -> Option<i64> {
+        self.total_bytes
+    }
+    /// The number of times that this download has failed
+    pub fn attempts(&self) -> i32 {
+        self.attempts
+    }
+    /// When this download will next be retried
+    pub fn next_attempt(&self) -> Option<&chrono::NaiveDateTime> {
+        self.next_attempt.as_ref()
+    }
+    /// The error from the most recent failure
+    pub fn last_error(&self) -> Option<&str> {
+        self.last_error.as_deref()
+    }
+    /// When this download was queued
+    pub fn created(&self) -> &chrono::NaiveDateTime {
+        &self.created
+    }
+    /// The length of the media according to the feed, if it gave a plausible one
+    pub fn expected_bytes(&self) -> Option<i64> {
+        self.expected_bytes
+    }
+    /// The Subresource Integrity hashes that the download should match
+    pub fn integrity(&self) -> Option<&str> {
+        self.integrity.as_deref()
+    }
+    /// Whether the download's length or integrity hash was checked once it was complete.
+    ///
+    /// Downloads with neither a length from the server or the feed nor an integrity hash can't
+    /// be checked, so they aren't verified.
+    pub fn is_verified(&self) -> bool {
+        self.verified
+    }
+    /// Whether the user asked for this download straight away, rather than it being queued
+    /// automatically. Manual downloads aren't held back by the download schedule.
+    pub fn is_manual(&self) -> bool {
+        self.manual
+    }
+
+    /// Queue `episode` to be downloaded from `enclosure` to `path`.
+    ///
+    /// If the episode is already queued, the existing download is returned, and becomes manual if
+    /// `manual` is set. Downloads that have failed are queued again. Downloads that there wasn't
+    /// room for are only queued again if `manual` is set, as they would just be refused again
+    /// until some room is freed.
+    pub fn enqueue(
+        con: &mut SqliteConnection,
+        episode: &Episode,
+        enclosure: &Enclosure,
+        path: &str,
+        manual: bool,
+    ) -> QueryResult<Download> {
+        con.transaction(|con| {
+            let existing: Option<Download> = downloads::table
+                .filter(downloads::episode_title.eq(episode.title()))
+                .filter(downloads::podcast_id.eq(episode.podcast_id()))
+                .first(con)
+                .optional()?;
+            if let Some(mut download) = existing {
+                let failed = match download.state() {
+                    DownloadState::Failed => true,
+                    DownloadState::NoRoom => manual,
+                    _ => false,
+                };
+                if failed {
+                    download.state = DownloadState::Queued.as_str().into();
+                    download.attempts = 0;
+                    download.next_attempt = None;
+                }
+                if failed || (manual && !download.manual) {
+                    download.manual |= manual;
+                    download.save(con)?;
+                }
+                Ok(download)
+            } else {
+                let _ = diesel::insert_into(downloads::table)
+                    .values(NewDownload {
+                        episode_title: episode.title().to_owned(),
+                        podcast_id: episode.podcast_id(),
+                        uri: enclosure.uri().to_owned(),
+                        path: path.to_owned(),
+                        state: DownloadState::Queued.as_str().into(),
+                        created: chrono::Utc::now().naive_utc(),
+                        // Plenty of feeds put a placeholder in the length, so only keep
+                        // lengths that could be an episode
+                        expected_bytes: enclosure
+                            .length()
+                            .filter(|length| *length >= MIN_PLAUSIBLE_LENGTH),
+                        integrity: enclosure.integrity().map(str::to_owned),
+                        manual,
+                    })
+                    .execute(con)?;
+                downloads::table
+                    .filter(downloads::episode_title.eq(episode.title()))
+                    .filter(downloads::podcast_id.eq(episode.podcast_id()))
+                    .first(con)
+            }
+        })
+    }
+
+    /// Whether a download of some other episode is already using `path`
+    pub fn path_in_use(
+        con: &mut SqliteConnection,
+        episode: &Episode,
+        path: &str,
+    ) -> QueryResult<bool> {
+        diesel::select(diesel::dsl::exists(
+            downloads::table.filter(downloads::path.eq(path)).filter(
+                downloads::episode_title
+                    .ne(episode.title())
+                    .or(downloads::podcast_id.ne(episode.podcast_id())),
+            ),
+        ))
+        .get_result(con)
+    }
+
+    /// Get every download that is queued or in progress, oldest first
+    pub fn pending(con: &mut SqliteConnection) -> QueryResult<Vec<Download>> {
+        downloads::table
+            .filter(downloads::state.eq_any([
+                DownloadState::Queued.as_str(),
+                DownloadState::Downloading.as_str(),
+            ]))
+            .order(downloads::created.asc())
+            .load(con)
+    }
+
+    /// Put any downloads that were interrupted by the application closing back in the queue
+    pub fn reset_interrupted(con: &mut SqliteConnection) -> QueryResult<usize> {
+        diesel::update(
+            downloads::table.filter(downloads::state.eq(DownloadState::Downloading.as_str())),
+        )
+        .set(downloads::state.eq(DownloadState::Queued.as_str()))
+        .execute(con)
+    }
+
+    /// Put the downloads that there wasn't room for back in the queue, once room may have been
+    /// freed or a quota raised. Only those of the podcast with ID `podcast_id` are queued again if
+    /// it is given.
+    pub fn retry_refused(
+        con: &mut SqliteConnection,
+        podcast_id: Option<i32>,
+    ) -> QueryResult<usize> {
+        let mut query = diesel::update(downloads::table)
+            .filter(downloads::state.eq(DownloadState::NoRoom.as_str()))
+            .into_boxed();
+        if let Some(podcast_id) = podcast_id {
+            query = query.filter(downloads::podcast_id.eq(podcast_id));
+        }
+        query
+            .set(downloads::state.eq(DownloadState::Queued.as_str()))
+            .execute(con)
+    }
+
+    /// Take the oldest queued download that is ready to start at `now`, marking it as downloading.
+    ///
+    /// Manual downloads go first. Automatic downloads are only considered if `automatic` is set.
+    pub fn claim_next(
+        con: &mut SqliteConnection,
+        now: chrono::NaiveDateTime,
+        automatic: bool,
+    ) -> QueryResult<Option<Download>> {
+        con.transaction(|con| {
+            let mut query = downloads::table
+                .filter(downloads::state.eq(DownloadState::Queued.as_str()))
+                .filter(
+                    downloads::next_attempt
+                        .is_null()
+                        .or(downloads::next_attempt.le(now)),
+                )
+                .order((downloads::manual.desc(), downloads::created.asc()))
+                .into_boxed();
+            if !automatic {
+                query = query.filter(downloads::manual.eq(true));
+            }
+            let next: Option<Download> = query.first(con).optional()?;
+            if let Some(mut download) = next {
+                download.state = DownloadState::Downloading.as_str().into();
+                download.save(con)?;
+                Ok(Some(download))
+            } else {
+                Ok(None)
+            }
+        })
+    }
+
+    /// The earliest time that a queued download is waiting to be retried.
+    ///
+    /// Automatic downloads are only considered if `automatic` is set.
+    pub fn next_retry(
+        con: &mut SqliteConnection,
+        automatic: bool,
+    ) -> QueryResult<Option<chrono::NaiveDateTime>> {
+        let mut query = downloads::table
+            .filter(downloads::state.eq(DownloadState::Queued.as_str()))
+            .select(diesel::dsl::min(downloads::next_attempt))
+            .into_boxed();
+        if !automatic {
+            query = query.filter(downloads::manual.eq(true));
+        }
+        query.first(con)
+    }
+
+    /// Record how much of the download has been saved
+    pub fn record_progress(&mut self, downloaded_bytes: i64, total_bytes: Option<i64>) {
+        self.downloaded_bytes = downloaded_bytes;
+        self.total_bytes = total_bytes.or(self.total_bytes);
+    }
+
+    /// Forget about any progress, such as when the partial download turned out to be corrupt
+    pub fn discard_progress(&mut self) {
+        self.downloaded_bytes = 0;
+        self.total_bytes = None;
+    }
+
+    /// Put the download back in the queue without counting it as a failure, such as when its
+    /// schedule window closes. Its progress is kept so that it can be resumed.
+    pub fn pause(&mut self) {
+        self.state = DownloadState::Queued.as_str().into();
+    }
+
+    /// Record that the download failed.
+    ///
+    /// The download is put back in the queue to be retried with an exponential backoff, unless it
+    /// has already failed `MAX_DOWNLOAD_ATTEMPTS` times.
+    pub fn record_failure(&mut self, error: &str) {
+        self.attempts = self.attempts.saturating_add(1);
+        self.last_error = Some(error.to_owned());
+        if self.attempts >= MAX_DOWNLOAD_ATTEMPTS {
+            self.state = DownloadState::Failed.as_str().into();
+            self.next_attempt = None;
+        } else {
+            let shift = u32::try_from(self.attempts - 1).unwrap_or(0).min(16);
+            let seconds = (RETRY_BASE_SECONDS << shift).min(RETRY_MAX_SECONDS);
+            self.state = DownloadState::Queued.as_str().into();
+            self.next_attempt =
+                Some(chrono::Utc::now().naive_utc() + chrono::Duration::seconds(seconds));
+        }
+    }
+
+    /// Hold the download back because there is no room for it, until
+    /// [`Download::retry_refused`] puts it back in the queue. Its progress is thrown away, as
+    /// the `.part` file is deleted to free its space.
+    pub fn refuse(&mut self, error: &str) {
+        self.state = DownloadState::NoRoom.as_str().into();
+        self.downloaded_bytes = 0;
+        self.total_bytes = None;
+        self.last_error = Some(error.to_owned());
+        self.next_attempt = None;
+    }
+
+    /// Whether this download will be retried after failing
+    pub fn will_retry(&self) -> bool {
+        self.state() == DownloadState::Queued
+    }
+
+    /// Mark the download as complete, and whether it could be `verified`, and point the episode's
+    /// local URI at it
+    pub fn complete(&mut self, con: &mut SqliteConnection, verified: bool) -> QueryResult<()> {
+        self.state = DownloadState::Completed.as_str().into();
+        self.verified = verified;
+        self.last_error = None;
+        self.next_attempt = None;
+        con.transaction(|con| {
+            self.save(con)?;
+            Episode::set_local_uri(con, self.podcast_id, &self.episode_title, Some(&self.path))
+        })
+    }
+
+    /// Forget that `episode` was downloaded, once its file has been deleted, so that it can be
+    /// downloaded again later.
+    ///
+    /// The space that it took up may be enough for downloads that there wasn't room for, so they
+    /// are queued again.
+    pub fn forget(con: &mut SqliteConnection, episode: &Episode) -> QueryResult<()> {
+        con.transaction(|con| {
+            let _ = diesel::delete(
+                downloads::table
+                    .filter(downloads::episode_title.eq(episode.title()))
+                    .filter(downloads::podcast_id.eq(episode.podcast_id())),
+            )
+            .execute(con)?;
+            let _ = Download::retry_refused(con, None)?;
+            Episode::set_local_uri(con, episode.podcast_id(), episode.title(), None)
+        })
+    }
+
+    /// Write any changes to this download back to the database
+    pub fn save(&self, con: &mut SqliteConnection) -> QueryResult<()> {
+        diesel::update(self).set(self).execute(con).map(|_| ())
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = downloads)]
+#[derive(Debug, Clone)]
+struct NewDownload {
+    episode_title: String,
+    podcast_id: i32,
+    uri: String,
+    path: String,
+    state: String,
+    created: chrono::NaiveDateTime,
+    expected_bytes: Option<i64>,
+    integrity: Option<String>,
+    manual: bool,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::{Removed, SelectionPolicy, Source};
+    use std::fs::File;
+    use std::io::BufReader;
+
+    #[test]
+    pub(crate) fn refused_downloads_wait_for_room() -> Result<(), Box<dyn std::error::Error>> {
+        let mut con = SqliteConnection::establish(":memory:")?;
+        crate::run_migration_on(&mut con)?;
+        let source = Source::get_or_create(&mut con, "https://atp.fm/rss")?;
+        let file = File::open("test-data/feeds/atp.xml")?;
+        let channel = rss::Channel::read_from(BufReader::new(file))?;
+        let podcast = crate::feed::index(&mut con, &source, &channel)?.podcast;
+        let episodes = Episode::for_podcast(&mut con, podcast.id(), Removed::Exclude)?;
+        let (episode, downloaded) = (&episodes[0], &episodes[1]);
+        let enclosure = Enclosure::choose(&mut con, episode, &SelectionPolicy::Default)?.unwrap();
+
+        let mut download = Download::enqueue(&mut con, episode, &enclosure, "/tmp/a.mp3", false)?;
+        download.refuse("No room");
+        download.save(&mut con)?;
+        // Refreshing again doesn't queue it up to be refused all over again
+        let again = Download::enqueue(&mut con, episode, &enclosure, "/tmp/a.mp3", false)?;
+        assert_eq!(again.state(), DownloadState::NoRoom);
+        assert!(Download::pending(&mut con)?.is_empty());
+
+        // Until the space of another download is freed
+        Episode::set_local_uri(
+            &mut con,
+            podcast.id(),
+            downloaded.title(),
+            Some("/tmp/b.mp3"),
+        )?;
+        Download::forget(&mut con, downloaded)?;
+        let pending = Download::pending(&mut con)?;
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].state(), DownloadState::Queued);
+
+        // Or the podcast's quota changes
+        let mut download = pending[0].clone();
+        download.refuse("No room");
+        download.save(&mut con)?;
+        crate::models::Podcast::set_max_download_bytes(&mut con, podcast.id(), Some(1 << 30))?;
+        assert_eq!(Download::pending(&mut con)?.len(), 1);
+        Ok(())
+    }
+}
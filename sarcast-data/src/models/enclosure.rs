@@ -0,0 +1,427 @@
+use super::Episode;
+use crate::media;
+use crate::schema::enclosures;
+use diesel::prelude::*;
+use rss;
+
+/// The audio codec of an enclosure
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    /// Opus, usually in an Ogg container
+    Opus,
+    /// AAC, usually in an MP4 container or as raw ADTS
+    Aac,
+    /// MPEG-1/2 Layer III
+    Mp3,
+    /// Vorbis, usually in an Ogg container
+    Vorbis,
+    /// Free Lossless Audio Codec
+    Flac,
+    /// A codec that we can't identify
+    Other,
+}
+
+impl Codec {
+    /// Work out the codec from a MIME type and an RFC 6381 `codecs` string.
+    ///
+    /// The `codecs` string is more specific, so it is preferred when it is recognised.
+    pub fn identify(mime_type: Option<&str>, codecs: Option<&str>) -> Codec {
+        let from_codecs = codecs.map(str::to_ascii_lowercase).and_then(|codecs| {
+            if codecs.contains("opus") {
+                Some(Codec::Opus)
+            } else if codecs.contains("mp3") || codecs.contains("mp4a.6b") {
+                Some(Codec::Mp3)
+            } else if codecs.contains("mp4a") || codecs.contains("aac") {
+                Some(Codec::Aac)
+            } else if codecs.contains("vorbis") {
+                Some(Codec::Vorbis)
+            } else if codecs.contains("flac") {
+                Some(Codec::Flac)
+            } else {
+                None
+            }
+        });
+        let from_mime = || match mime_type.and_then(media::normalize_mime_type)?.as_str() {
+            "audio/opus" => Some(Codec::Opus),
+            "audio/mpeg" => Some(Codec::Mp3),
+            "audio/mp4" | "audio/aac" => Some(Codec::Aac),
+            "audio/ogg" | "audio/vorbis" => Some(Codec::Vorbis),
+            "audio/flac" => Some(Codec::Flac),
+            _ => None,
+        };
+        from_codecs.or_else(from_mime).unwrap_or(Codec::Other)
+    }
+}
+
+/// How to choose between the enclosures that are offered for an episode
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SelectionPolicy {
+    /// Use whatever the publisher has marked as the default
+    Default,
+    /// Use the smallest file, such as when on a metered connection
+    Smallest,
+    /// Use the highest bitrate
+    HighestQuality,
+    /// Use the first codec in the list that is offered, falling back to the default
+    PreferCodecs(Vec<Codec>),
+}
+
+#[derive(Queryable, Identifiable, PartialEq)]
+#[diesel(table_name = enclosures)]
+#[derive(Debug, Clone)]
+/// Diesel Model of the enclosures table.
+pub struct Enclosure {
+    id: i32,
+    episode_title: String,
+    podcast_id: i32,
+    uri: String,
+    mime_type: Option<String>,
+    length: Option<i64>,
+    bitrate: Option<i32>,
+    codecs: Option<String>,
+    title: Option<String>,
+    is_default: bool,
+    integrity: Option<String>,
+}
+
+impl Enclosure {
+    /// The row ID of this enclosure
+    pub fn id(&self) -> i32 {
+        self.id
+    }
+    /// The title of the episode that this is an enclosure for
+    pub fn episode_title(&self) -> &str {
+        &self.episode_title
+    }
+    /// The ID of the show that this enclosure is from
+    pub fn podcast_id(&self) -> i32 {
+        self.podcast_id
+    }
+    /// The URI of the media
+    pub fn uri(&self) -> &str {
+        &self.uri
+    }
+    /// The MIME type of the media
+    pub fn mime_type(&self) -> Option<&str> {
+        self.mime_type.as_deref()
+    }
+    /// The length of the media in bytes
+    pub fn length(&self) -> Option<i64> {
+        self.length
+    }
+    /// The bitrate of the media in bits per second
+    pub fn bitrate(&self) -> Option<i32> {
+        self.bitrate
+    }
+    /// The RFC 6381 codecs string of the media
+    pub fn codecs(&self) -> Option<&str> {
+        self.codecs.as_deref()
+    }
+    /// A human readable description of this version of the media
+    pub fn title(&self) -> Option<&str> {
+        self.title.as_deref()
+    }
+    /// Whether this is the main `<enclosure>` of the episode
+    pub fn is_default(&self) -> bool {
+        self.is_default
+    }
+    /// The Subresource Integrity hashes of the media, like `sha256-<base64 digest>`
+    pub fn integrity(&self) -> Option<&str> {
+        self.integrity.as_deref()
+    }
+    /// The audio codec of the media
+    pub fn codec(&self) -> Codec {
+        Codec::identify(self.mime_type(), self.codecs())
+    }
+
+    /// Get every enclosure that is offered for `episode`, with the default first
+    pub fn for_episode(
+        con: &mut SqliteConnection,
+        episode: &Episode,
+    ) -> QueryResult<Vec<Enclosure>> {
+        enclosures::table
+            .filter(enclosures::episode_title.eq(episode.title()))
+            .filter(enclosures::podcast_id.eq(episode.podcast_id()))
+            .order((enclosures::is_default.desc(), enclosures::id.asc()))
+            .load(con)
+    }
+
+    /// Choose one of `enclosures` according to `policy`
+    pub fn select<'a>(
+        enclosures: &'a [Enclosure],
+        policy: &SelectionPolicy,
+    ) -> Option<&'a Enclosure> {
+        let default = || {
+            enclosures
+                .iter()
+                .find(|e| e.is_default)
+                .or_else(|| enclosures.first())
+        };
+        match policy {
+            SelectionPolicy::Default => default(),
+            // Enclosures without a length can't be compared, so they are only used as a last
+            // resort, falling back to the bitrate.
+            SelectionPolicy::Smallest => enclosures
+                .iter()
+                .min_by_key(|e| (e.length.unwrap_or(i64::MAX), e.bitrate.unwrap_or(i32::MAX))),
+            SelectionPolicy::HighestQuality => enclosures
+                .iter()
+                .filter(|e| e.bitrate.is_some() || e.length.is_some())
+                .max_by_key(|e| (e.bitrate, e.length))
+                .or_else(default),
+            SelectionPolicy::PreferCodecs(codecs) => codecs
+                .iter()
+                .find_map(|codec| {
+                    let mut matching = enclosures.iter().filter(|e| e.codec() == *codec);
+                    let first = matching.next()?;
+                    Some(
+                        std::iter::once(first)
+                            .chain(matching)
+                            .find(|e| e.is_default)
+                            .unwrap_or(first),
+                    )
+                })
+                .or_else(default),
+        }
+    }
+
+    /// Get the enclosure to use for `episode` according to `policy`
+    pub fn choose(
+        con: &mut SqliteConnection,
+        episode: &Episode,
+        policy: &SelectionPolicy,
+    ) -> QueryResult<Option<Enclosure>> {
+        let enclosures = Self::for_episode(con, episode)?;
+        Ok(Self::select(&enclosures, policy).cloned())
+    }
+}
+
+///
+#[derive(Insertable)]
+#[diesel(table_name = enclosures)]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NewEnclosure {
+    episode_title: String,
+    podcast_id: i32,
+    uri: String,
+    mime_type: Option<String>,
+    length: Option<i64>,
+    bitrate: Option<i32>,
+    codecs: Option<String>,
+    title: Option<String>,
+    is_default: bool,
+    integrity: Option<String>,
+}
+
+impl NewEnclosure {
+    /// The title of the episode that this is an enclosure for
+    pub fn episode_title(&self) -> &str {
+        &self.episode_title
+    }
+    /// The URI of the media
+    pub fn uri(&self) -> &str {
+        &self.uri
+    }
+
+    /// Get every enclosure that is offered by an item.
+    ///
+    /// The main `<enclosure>` comes first, followed by any `<podcast:alternateEnclosure>`s that
+    /// can be fetched over HTTP.
+    /// <https://github.com/Podcastindex-org/podcast-namespace/blob/main/docs/1.0.md#alternate-enclosure>
+    pub fn from_rss(item: &rss::Item, podcast_id: i32) -> Vec<NewEnclosure> {
+        let episode_title = match item.title() {
+            Some(title) => title.trim().to_owned(),
+            None => return vec![],
+        };
+        let mut found: Vec<NewEnclosure> = vec![];
+
+        if let Some(enc) = item.enclosure() {
+            found.push(NewEnclosure {
+                episode_title: episode_title.clone(),
+                podcast_id,
+                uri: enc.url().trim().to_owned(),
+                mime_type: media::normalize_mime_type(enc.mime_type()),
+                length: enc.length().trim().parse().ok(),
+                is_default: true,
+                ..Default::default()
+            });
+        }
+
+        let alternates = item
+            .extensions()
+            .get("podcast")
+            .and_then(|podcast| podcast.get("alternateEnclosure"))
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        for alternate in alternates {
+            let uri = alternate
+                .children
+                .get("source")
+                .into_iter()
+                .flatten()
+                .filter_map(|source| source.attrs.get("uri"))
+                .map(|uri| uri.trim())
+                .find(|uri| uri.starts_with("https://") || uri.starts_with("http://"));
+            let uri = match uri {
+                Some(uri) => uri.to_owned(),
+                None => continue,
+            };
+            let attr = |name: &str| alternate.attrs.get(name).map(|value| value.trim());
+            let enclosure = NewEnclosure {
+                episode_title: episode_title.clone(),
+                podcast_id,
+                uri,
+                mime_type: attr("type").and_then(media::normalize_mime_type),
+                length: attr("length").and_then(|length| length.parse().ok()),
+                bitrate: attr("bitrate").and_then(bitrate),
+                codecs: attr("codecs").map(str::to_owned),
+                title: attr("title").map(str::to_owned),
+                is_default: false,
+                integrity: integrity(alternate),
+            };
+            // The default alternate is usually the main enclosure again, but it can tell us more
+            // about it.
+            match found.iter_mut().find(|found| found.uri == enclosure.uri) {
+                Some(existing) => existing.fill_from(enclosure),
+                None => found.push(enclosure),
+            }
+        }
+        found
+    }
+
+    fn fill_from(&mut self, other: NewEnclosure) {
+        self.mime_type = self.mime_type.take().or(other.mime_type);
+        self.length = self.length.or(other.length);
+        self.bitrate = self.bitrate.or(other.bitrate);
+        self.codecs = self.codecs.take().or(other.codecs);
+        self.title = self.title.take().or(other.title);
+        self.integrity = self.integrity.take().or(other.integrity);
+    }
+}
+
+/// Parse the bitrate of an alternate enclosure, which may have a fractional part
+#[allow(clippy::cast_possible_truncation)]
+fn bitrate(bitrate: &str) -> Option<i32> {
+    let bitrate = bitrate
+        .parse::<f64>()
+        .ok()
+        .filter(|bitrate| bitrate.is_finite())?;
+    // Clamped to the range of an i32 and rounded first, so the cast can't truncate
+    Some(bitrate.round().clamp(0.0, f64::from(i32::MAX)) as i32)
+}
+
+/// Get the SRI hashes from the `<podcast:integrity>` of an alternate enclosure.
+///
+/// PGP signatures are ignored, as there's no key to check them against.
+fn integrity(alternate: &rss::extension::Extension) -> Option<String> {
+    alternate
+        .children
+        .get("integrity")
+        .into_iter()
+        .flatten()
+        .filter(|integrity| {
+            matches!(integrity.attrs.get("type"), Some(kind) if kind.trim().eq_ignore_ascii_case("sri"))
+        })
+        .find_map(|integrity| integrity.attrs.get("value"))
+        .map(|value| value.trim().to_owned())
+        .filter(|value| !value.is_empty())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ITEM: &str = r#"<rss version="2.0" xmlns:podcast="https://podcastindex.org/namespace/1.0">
+    <channel><title>Show</title><link>https://example.com</link><description/>
+    <item>
+        <title> Episode 1 </title>
+        <enclosure url="https://example.com/ep1.mp3" length="60000000" type="audio/mpeg"/>
+        <podcast:alternateEnclosure type="audio/mpeg" length="60000000" bitrate="128000" default="true">
+            <podcast:source uri="https://example.com/ep1.mp3"/>
+            <podcast:integrity type="sri" value="sha384-ExVqijgYHm15PqQqdXfW95x+Rs6C+d6E/ICxyQOeFevnxNLR/wtJNrNYTjIysUBo"/>
+        </podcast:alternateEnclosure>
+        <podcast:alternateEnclosure type="audio/opus" length="15000000" bitrate="32000" title="Low bandwidth">
+            <podcast:source uri="ipfs://QmdwGqd3d2gFPGeJNLLCshdiPert45fMu84552Y4XHTy4y"/>
+            <podcast:source uri="https://example.com/ep1.opus"/>
+        </podcast:alternateEnclosure>
+        <podcast:alternateEnclosure type="audio/mp4" length="30000000" bitrate="64000" codecs="mp4a.40.5">
+            <podcast:source uri="https://example.com/ep1.m4a"/>
+        </podcast:alternateEnclosure>
+        <podcast:alternateEnclosure type="audio/flac" length="300000000">
+            <podcast:source uri="magnet:?xt=urn:btih:deadbeef"/>
+        </podcast:alternateEnclosure>
+    </item>
+    </channel></rss>"#;
+
+    fn enclosures() -> Result<Vec<Enclosure>, Box<dyn std::error::Error>> {
+        let channel = rss::Channel::read_from(ITEM.as_bytes())?;
+        let found = NewEnclosure::from_rss(&channel.items()[0], 1);
+        Ok(found
+            .into_iter()
+            .zip(1..)
+            .map(|(new, id)| Enclosure {
+                id,
+                episode_title: new.episode_title,
+                podcast_id: new.podcast_id,
+                uri: new.uri,
+                mime_type: new.mime_type,
+                length: new.length,
+                bitrate: new.bitrate,
+                codecs: new.codecs,
+                title: new.title,
+                is_default: new.is_default,
+                integrity: new.integrity,
+            })
+            .collect())
+    }
+
+    #[test]
+    pub(crate) fn select_alternate_enclosures() -> Result<(), Box<dyn std::error::Error>> {
+        let enclosures = enclosures()?;
+        let uris: Vec<&str> = enclosures.iter().map(Enclosure::uri).collect();
+        assert_eq!(
+            uris,
+            [
+                "https://example.com/ep1.mp3",
+                "https://example.com/ep1.opus",
+                "https://example.com/ep1.m4a"
+            ]
+        );
+        assert_eq!(enclosures[0].episode_title(), "Episode 1");
+        assert_eq!(enclosures[1].title(), Some("Low bandwidth"));
+        assert_eq!(enclosures[2].codec(), Codec::Aac);
+        assert_eq!(
+            enclosures[0].integrity(),
+            Some("sha384-ExVqijgYHm15PqQqdXfW95x+Rs6C+d6E/ICxyQOeFevnxNLR/wtJNrNYTjIysUBo")
+        );
+        assert_eq!(enclosures[1].integrity(), None);
+
+        let select = |policy| Enclosure::select(&enclosures, &policy).map(Enclosure::uri);
+        assert_eq!(
+            select(SelectionPolicy::Default),
+            Some("https://example.com/ep1.mp3")
+        );
+        assert_eq!(
+            select(SelectionPolicy::Smallest),
+            Some("https://example.com/ep1.opus")
+        );
+        assert_eq!(enclosures[0].bitrate(), Some(128_000));
+        assert_eq!(bitrate("64000.4"), Some(64_000));
+        assert_eq!(bitrate("-1"), Some(0));
+        assert_eq!(bitrate("1e12"), Some(i32::MAX));
+        assert_eq!(bitrate("NaN"), None);
+        assert_eq!(
+            select(SelectionPolicy::HighestQuality),
+            Some("https://example.com/ep1.mp3")
+        );
+        assert_eq!(
+            select(SelectionPolicy::PreferCodecs(vec![Codec::Aac, Codec::Opus])),
+            Some("https://example.com/ep1.m4a")
+        );
+        assert_eq!(
+            select(SelectionPolicy::PreferCodecs(vec![Codec::Vorbis])),
+            Some("https://example.com/ep1.mp3")
+        );
+        Ok(())
+    }
+}
@@ -1,7 +1,24 @@
 use super::Podcast;
+use crate::media::{self, MediaKind};
 use crate::schema::episodes;
 use diesel::prelude::*;
 use rss;
+use std::collections::HashSet;
+
+/// The most titles that are compared against in one query, to stay well under the database's
+/// limit on bound variables
+const MAX_TITLES_PER_QUERY: usize = 500;
+
+/// Whether to include episodes that have been removed from their feed in a query
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Removed {
+    /// Only episodes that are still in the feed
+    Exclude,
+    /// Every episode, whether or not it is still in the feed
+    Include,
+    /// Only episodes that have been removed from the feed
+    Only,
+}
 
 #[derive(Queryable, Identifiable, AsChangeset, Associations, PartialEq)]
 #[diesel(table_name = episodes)]
@@ -15,13 +32,21 @@ pub struct Episode {
     uri: Option<String>,
     local_uri: Option<String>,
     description: Option<String>,
-    epoch: i32,
-    length: Option<i32>,
+    epoch: i64,
+    length: Option<i64>,
     duration: Option<i32>,
     guid: Option<String>,
-    played: Option<i32>,
+    played: Option<i64>,
     play_position: i32,
     podcast_id: i32,
+    removed_upstream: Option<chrono::NaiveDateTime>,
+    mime_type: Option<String>,
+    file_extension: Option<String>,
+    season: Option<i32>,
+    episode_number: Option<i32>,
+    episode_type: Option<String>,
+    chapters_uri: Option<String>,
+    time_saved: i64,
 }
 
 impl Episode {
@@ -42,11 +67,11 @@ impl Episode {
         self.description.as_deref()
     }
     /// The epoch that this episode was published at
-    pub fn epoch(&self) -> i32 {
+    pub fn epoch(&self) -> i64 {
         self.epoch
     }
     /// The length of the podcast in bytes
-    pub fn length(&self) -> Option<i32> {
+    pub fn length(&self) -> Option<i64> {
         self.length
     }
     /// The duration of the episode in milliseconds
@@ -58,7 +83,7 @@ impl Episode {
         self.guid.as_deref()
     }
     /// When this episode was last played
-    pub fn played(&self) -> Option<i32> {
+    pub fn played(&self) -> Option<i64> {
         self.played
     }
     /// The position that this episode is last in for playing
@@ -69,6 +94,229 @@ impl Episode {
     pub fn podcast_id(&self) -> i32 {
         self.podcast_id
     }
+    /// When this episode was found to be missing from its feed
+    pub fn removed_upstream(&self) -> Option<&chrono::NaiveDateTime> {
+        self.removed_upstream.as_ref()
+    }
+    /// Whether this episode has been removed from its feed
+    pub fn is_removed_upstream(&self) -> bool {
+        self.removed_upstream.is_some()
+    }
+    /// Whether this episode has been downloaded but is no longer available from its feed.
+    ///
+    /// The local copy of these episodes is kept, as it may be the only one left.
+    pub fn is_orphaned_download(&self) -> bool {
+        self.removed_upstream.is_some() && self.local_uri.is_some()
+    }
+
+    /// The MIME type of the playable media for this episode
+    pub fn mime_type(&self) -> Option<&str> {
+        self.mime_type.as_deref()
+    }
+    /// The file extension of the playable media for this episode
+    pub fn file_extension(&self) -> Option<&str> {
+        self.file_extension.as_deref()
+    }
+    /// The season that this episode is in
+    pub fn season(&self) -> Option<i32> {
+        self.season
+    }
+    /// The number of this episode, within its season if it has one
+    pub fn episode_number(&self) -> Option<i32> {
+        self.episode_number
+    }
+    /// Whether this is a `full` episode, a `trailer` or a `bonus`
+    pub fn episode_type(&self) -> Option<&str> {
+        self.episode_type.as_deref()
+    }
+    /// Whether the feed marks this episode as a trailer
+    pub fn is_trailer(&self) -> bool {
+        self.episode_type() == Some("trailer")
+    }
+    /// Where the feed publishes the chapters of this episode as JSON, which [`crate::chapters`]
+    /// can read
+    pub fn chapters_uri(&self) -> Option<&str> {
+        self.chapters_uri.as_deref()
+    }
+    /// How much listening time silence trimming has saved on this episode, in milliseconds
+    pub fn time_saved(&self) -> i64 {
+        self.time_saved
+    }
+    /// Whether this episode is audio or video
+    pub fn media_kind(&self) -> MediaKind {
+        MediaKind::classify(self.mime_type(), self.file_extension())
+    }
+
+    /// Get the episode of a podcast with the title `title`
+    pub fn find(
+        con: &mut SqliteConnection,
+        podcast_id: i32,
+        title: &str,
+    ) -> QueryResult<Option<Episode>> {
+        episodes::table
+            .find((title, podcast_id))
+            .first(con)
+            .optional()
+    }
+
+    /// Get the episodes of a podcast, newest first
+    pub fn for_podcast(
+        con: &mut SqliteConnection,
+        podcast_id: i32,
+        removed: Removed,
+    ) -> QueryResult<Vec<Episode>> {
+        let query = episodes::table
+            .filter(episodes::podcast_id.eq(podcast_id))
+            .order(episodes::epoch.desc())
+            .into_boxed();
+        let query = match removed {
+            Removed::Exclude => query.filter(episodes::removed_upstream.is_null()),
+            Removed::Include => query,
+            Removed::Only => query.filter(episodes::removed_upstream.is_not_null()),
+        };
+        query.load(con)
+    }
+
+    /// Set where the episode with the title `title` has been downloaded to
+    pub fn set_local_uri(
+        con: &mut SqliteConnection,
+        podcast_id: i32,
+        title: &str,
+        local_uri: Option<&str>,
+    ) -> QueryResult<()> {
+        diesel::update(episodes::table.find((title, podcast_id)))
+            .set(episodes::local_uri.eq(local_uri))
+            .execute(con)
+            .map(|_| ())
+    }
+
+    /// Set the duration of the episode with the title `title`, in milliseconds
+    pub fn set_duration(
+        con: &mut SqliteConnection,
+        podcast_id: i32,
+        title: &str,
+        duration: Option<i32>,
+    ) -> QueryResult<()> {
+        diesel::update(episodes::table.find((title, podcast_id)))
+            .set(episodes::duration.eq(duration))
+            .execute(con)
+            .map(|_| ())
+    }
+
+    /// Set when the episode with the title `title` was played to the end, as a Unix timestamp,
+    /// or `None` to mark it as unplayed.
+    ///
+    /// Marking it as played also rewinds it, so that playing it again starts from the beginning
+    /// rather than just before the end.
+    pub fn set_played(
+        con: &mut SqliteConnection,
+        podcast_id: i32,
+        title: &str,
+        played: Option<i64>,
+    ) -> QueryResult<()> {
+        let episode = episodes::table.find((title, podcast_id));
+        match played {
+            Some(_) => diesel::update(episode)
+                .set((episodes::played.eq(played), episodes::play_position.eq(0)))
+                .execute(con),
+            None => diesel::update(episode)
+                .set(episodes::played.eq(played))
+                .execute(con),
+        }
+        .map(|_| ())
+    }
+
+    /// Set where playback of the episode with the title `title` got to, in milliseconds, so that
+    /// it can be resumed from there
+    pub fn set_play_position(
+        con: &mut SqliteConnection,
+        podcast_id: i32,
+        title: &str,
+        play_position: i32,
+    ) -> QueryResult<()> {
+        diesel::update(episodes::table.find((title, podcast_id)))
+            .set(episodes::play_position.eq(play_position))
+            .execute(con)
+            .map(|_| ())
+    }
+
+    /// Add `saved` milliseconds to the listening time that silence trimming has saved on the
+    /// episode with the title `title`
+    pub fn add_time_saved(
+        con: &mut SqliteConnection,
+        podcast_id: i32,
+        title: &str,
+        saved: i64,
+    ) -> QueryResult<()> {
+        diesel::update(episodes::table.find((title, podcast_id)))
+            .set(episodes::time_saved.eq(episodes::time_saved + saved))
+            .execute(con)
+            .map(|_| ())
+    }
+
+    /// Mark the episodes of a podcast that are missing from `seen` as removed upstream.
+    ///
+    /// Episodes that were previously removed but are back in `seen` are restored. Returns the
+    /// episodes that were newly marked as removed.
+    ///
+    /// Nothing is marked if `seen` is empty, or has less than half of the episodes that were still
+    /// in the feed, as a feed that suddenly loses most of its episodes is far more likely to be
+    /// broken than to have had them pulled.
+    pub fn mark_removed(
+        con: &mut SqliteConnection,
+        podcast_id: i32,
+        seen: &[NewEpisode],
+    ) -> QueryResult<Vec<Episode>> {
+        let seen: HashSet<&str> = seen.iter().map(NewEpisode::title).collect();
+        let now = chrono::Utc::now().naive_utc();
+        con.transaction(|con| {
+            let known: Vec<Episode> = episodes::table
+                .filter(episodes::podcast_id.eq(podcast_id))
+                .load(con)?;
+            let (missing, present): (Vec<Episode>, Vec<Episode>) = known
+                .into_iter()
+                .partition(|episode| !seen.contains(episode.title()));
+
+            let restored: Vec<&str> = present
+                .iter()
+                .filter(|episode| episode.is_removed_upstream())
+                .map(Episode::title)
+                .collect();
+            for titles in restored.chunks(MAX_TITLES_PER_QUERY) {
+                let _ = diesel::update(
+                    episodes::table
+                        .filter(episodes::podcast_id.eq(podcast_id))
+                        .filter(episodes::title.eq_any(titles)),
+                )
+                .set(episodes::removed_upstream.eq(None::<chrono::NaiveDateTime>))
+                .execute(con)?;
+            }
+
+            let mut removed: Vec<Episode> = missing
+                .into_iter()
+                .filter(|episode| !episode.is_removed_upstream())
+                .collect();
+            // How many episodes were in the feed the last time that it was read
+            let in_feed = present.len() - restored.len() + removed.len();
+            if seen.is_empty() || seen.len() * 2 < in_feed {
+                return Ok(vec![]);
+            }
+            let titles: Vec<&str> = removed.iter().map(Episode::title).collect();
+            for titles in titles.chunks(MAX_TITLES_PER_QUERY) {
+                let _ = diesel::update(
+                    episodes::table
+                        .filter(episodes::podcast_id.eq(podcast_id))
+                        .filter(episodes::title.eq_any(titles)),
+                )
+                .set(episodes::removed_upstream.eq(now))
+                .execute(con)?;
+            }
+            for episode in &mut removed {
+                episode.removed_upstream = Some(now);
+            }
+            Ok(removed)
+        })
+    }
 }
 
 ///
@@ -79,12 +327,18 @@ pub struct NewEpisode {
     title: String,
     uri: Option<String>,
     description: Option<String>,
-    length: Option<i32>,
+    length: Option<i64>,
     duration: Option<i32>,
     play_position: i32,
     guid: Option<String>,
-    epoch: i32,
+    epoch: i64,
     podcast_id: i32,
+    mime_type: Option<String>,
+    file_extension: Option<String>,
+    season: Option<i32>,
+    episode_number: Option<i32>,
+    episode_type: Option<String>,
+    chapters_uri: Option<String>,
 }
 
 impl TryFrom<(&rss::Item, &Podcast)> for NewEpisode {
@@ -117,36 +371,139 @@ impl TryFrom<(&rss::Item, i32)> for NewEpisode {
         // Get the size of the content, it should be in bytes
         let length = enc.and_then(|x| x.length().parse().ok());
 
+        // Get the type of the content, preferring the extension in the url as it is what the
+        // file will be saved as.
+        let mime_type = enc.and_then(|x| media::normalize_mime_type(x.mime_type()));
+        let file_extension = uri
+            .as_deref()
+            .and_then(media::extension_from_uri)
+            .or_else(|| {
+                mime_type
+                    .as_deref()
+                    .and_then(media::extension_for_mime_type)
+                    .map(str::to_owned)
+            });
+
         // If url is still None return an Error as this behaviour is not
         // compliant with the RSS Spec.
         if uri.is_none() {
             return Err("No url specified for the item.".into());
         };
 
-        // Default to rfc2822 representation of epoch 0.
-        let date = chrono::DateTime::parse_from_rfc2822(
-            item.pub_date().unwrap_or("Thu, 1 Jan 1970 00:00:00 +0000"),
-        );
-        // If the date is invalid, just take the 0 epoch
-        let epoch = date.map(|x| x.timestamp() as i32).unwrap_or(0);
+        // Feeds are very loose with their dates, so be lenient when parsing them. If there is no
+        // date, or it is still unreadable, just take the 0 epoch.
+        let epoch = item
+            .pub_date()
+            .and_then(crate::date::parse_timestamp)
+            .unwrap_or(0);
 
         let description = item.description().map(|s| s.to_owned());
+        let duration = item
+            .itunes_ext()
+            .and_then(|ext| ext.duration())
+            .and_then(crate::date::parse_duration);
+
+        // Prefer the iTunes tags, as they are far more common than the podcast namespace
+        let number = |itunes: Option<&str>, podcast: &str| {
+            itunes
+                .or_else(|| {
+                    item.extensions()
+                        .get("podcast")?
+                        .get(podcast)?
+                        .first()?
+                        .value()
+                })
+                .and_then(|number| number.trim().parse::<i32>().ok())
+        };
+        let season = number(item.itunes_ext().and_then(|ext| ext.season()), "season");
+        let episode_number = number(item.itunes_ext().and_then(|ext| ext.episode()), "episode");
+        let episode_type = item
+            .itunes_ext()
+            .and_then(|ext| ext.episode_type())
+            .map(|kind| kind.trim().to_ascii_lowercase())
+            .filter(|kind| !kind.is_empty());
+        // Only the JSON format is understood, but feeds often leave the type out
+        let chapters_uri =
+            item.extensions()
+                .get("podcast")
+                .and_then(|podcast| podcast.get("chapters"))
+                .into_iter()
+                .flatten()
+                .filter(|chapters| {
+                    chapters.attrs.get("type").is_none_or(|kind| {
+                        kind.trim().eq_ignore_ascii_case(crate::chapters::MIME_TYPE)
+                    })
+                })
+                .find_map(|chapters| chapters.attrs.get("url"))
+                .map(|uri| uri.trim().to_owned())
+                .filter(|uri| !uri.is_empty());
 
         Ok(NewEpisode {
             title,
             uri,
             length,
-            duration: None,
+            duration,
             play_position: 0,
             description,
             epoch,
             guid,
             podcast_id,
+            mime_type,
+            file_extension,
+            season,
+            episode_number,
+            episode_type,
+            chapters_uri,
         })
     }
 }
 
 impl NewEpisode {
+    /// The title of this episode
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+    /// The URI to the playable media for this episode
+    pub fn uri(&self) -> Option<&str> {
+        self.uri.as_deref()
+    }
+    /// The ID of the show that this episode is from
+    pub fn podcast_id(&self) -> i32 {
+        self.podcast_id
+    }
+    /// The duration of the episode in milliseconds, according to the feed
+    pub fn duration(&self) -> Option<i32> {
+        self.duration
+    }
+    /// The episode's `guid`
+    pub fn guid(&self) -> Option<&str> {
+        self.guid.as_deref()
+    }
+    /// The MIME type of the playable media for this episode
+    pub fn mime_type(&self) -> Option<&str> {
+        self.mime_type.as_deref()
+    }
+    /// The file extension of the playable media for this episode
+    pub fn file_extension(&self) -> Option<&str> {
+        self.file_extension.as_deref()
+    }
+    /// The season that this episode is in
+    pub fn season(&self) -> Option<i32> {
+        self.season
+    }
+    /// The number of this episode, within its season if it has one
+    pub fn episode_number(&self) -> Option<i32> {
+        self.episode_number
+    }
+    /// Where the feed publishes the chapters of this episode as JSON
+    pub fn chapters_uri(&self) -> Option<&str> {
+        self.chapters_uri.as_deref()
+    }
+    /// Whether this episode is audio or video
+    pub fn media_kind(&self) -> MediaKind {
+        MediaKind::classify(self.mime_type(), self.file_extension())
+    }
+
     ///
     pub fn from_rss(
         item: &rss::Item,
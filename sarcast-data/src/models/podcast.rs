@@ -1,4 +1,4 @@
-use super::Source;
+use super::{Download, Source};
 use crate::schema::podcasts;
 use diesel::prelude::*;
 use rss;
@@ -17,6 +17,7 @@ pub struct Podcast {
     image_uri: Option<String>,
     image_cached: chrono::NaiveDateTime,
     source_id: i32,
+    max_download_bytes: Option<i64>,
 }
 
 impl Podcast {
@@ -48,6 +49,40 @@ impl Podcast {
     pub fn source_id(&self) -> i32 {
         self.source_id
     }
+    /// The most space that this podcast's downloads may take up, in bytes
+    pub fn max_download_bytes(&self) -> Option<i64> {
+        self.max_download_bytes
+    }
+
+    /// Get the podcast with ID `id`
+    pub fn find(con: &mut SqliteConnection, id: i32) -> QueryResult<Option<Podcast>> {
+        podcasts::table.find(id).first(con).optional()
+    }
+
+    /// Set the most space that the downloads of the podcast with ID `id` may take up, or `None`
+    /// for no limit other than the global one.
+    ///
+    /// Its downloads that there wasn't room for are queued again, as they may fit now.
+    pub fn set_max_download_bytes(
+        con: &mut SqliteConnection,
+        id: i32,
+        max_download_bytes: Option<i64>,
+    ) -> QueryResult<()> {
+        con.transaction(|con| {
+            let _ = diesel::update(podcasts::table.find(id))
+                .set(podcasts::max_download_bytes.eq(max_download_bytes))
+                .execute(con)?;
+            Download::retry_refused(con, Some(id)).map(|_| ())
+        })
+    }
+
+    /// Get the podcast that is read from the `Source` with ID `source_id`
+    pub fn for_source(con: &mut SqliteConnection, source_id: i32) -> QueryResult<Option<Podcast>> {
+        podcasts::table
+            .filter(podcasts::source_id.eq(source_id))
+            .first(con)
+            .optional()
+    }
 }
 
 ///
@@ -66,7 +101,7 @@ pub struct NewPodcast {
 impl TryFrom<(&rss::Channel, &Source)> for NewPodcast {
     type Error = <Self as TryFrom<(&'static rss::Channel, i32)>>::Error;
     fn try_from((channel, source): (&rss::Channel, &Source)) -> Result<Self, Self::Error> {
-        Self::try_from((channel, source.id))
+        Self::try_from((channel, source.id()))
     }
 }
 
@@ -101,6 +136,23 @@ impl NewPodcast {
     ) -> Result<Self, <Self as TryFrom<(&rss::Channel, i32)>>::Error> {
         Self::try_from((channel, source_id))
     }
+
+    /// Insert this podcast, or update the podcast that already exists for its `Source`
+    pub fn upsert(&self, con: &mut SqliteConnection) -> QueryResult<Podcast> {
+        match Podcast::for_source(con, self.source_id)? {
+            Some(existing) => {
+                let _ = diesel::update(&existing).set(self).execute(con)?;
+            }
+            None => {
+                let _ = diesel::insert_into(podcasts::table)
+                    .values(self)
+                    .execute(con)?;
+            }
+        }
+        podcasts::table
+            .filter(podcasts::source_id.eq(self.source_id))
+            .first(con)
+    }
 }
 
 #[cfg(test)]
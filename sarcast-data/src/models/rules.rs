@@ -0,0 +1,289 @@
+use super::{Episode, Podcast};
+use crate::schema::{download_rules, episodes};
+use diesel::prelude::*;
+
+/// How many of the newest unplayed episodes are downloaded when no number has been set
+pub const DEFAULT_NEWEST_UNPLAYED: i32 = 3;
+
+/// Which episodes of a podcast are downloaded automatically after each refresh, and when their
+/// downloads are deleted again.
+///
+/// Only played episodes are ever deleted, and never ones that have been removed from the feed,
+/// as the download may be the only copy left.
+#[derive(Queryable, Identifiable, Insertable, AsChangeset, PartialEq)]
+#[diesel(table_name = download_rules)]
+#[diesel(treat_none_as_null = true)]
+#[diesel(primary_key(podcast_id))]
+#[derive(Debug, Clone)]
+pub struct DownloadRules {
+    podcast_id: i32,
+    newest_unplayed: Option<i32>,
+    title_patterns: Option<String>,
+    skip_trailers: bool,
+    delete_played_after_days: Option<i32>,
+    keep_latest: Option<i32>,
+}
+
+impl DownloadRules {
+    /// Create the default rules for `podcast`, which download the newest few unplayed episodes
+    /// other than trailers, and never delete anything
+    pub fn new(podcast: &Podcast) -> Self {
+        DownloadRules {
+            podcast_id: podcast.id(),
+            newest_unplayed: Some(DEFAULT_NEWEST_UNPLAYED),
+            title_patterns: None,
+            skip_trailers: true,
+            delete_played_after_days: None,
+            keep_latest: None,
+        }
+    }
+
+    /// The ID of the podcast that these rules are for
+    pub fn podcast_id(&self) -> i32 {
+        self.podcast_id
+    }
+    /// How many of the newest unplayed episodes to keep downloaded, or `None` for all of them
+    pub fn newest_unplayed(&self) -> Option<i32> {
+        self.newest_unplayed
+    }
+    /// The patterns that episode titles are matched against, one per line.
+    ///
+    /// A pattern matches any title that contains it, ignoring case, and `*` matches any run of
+    /// characters. Patterns starting with `!` exclude the titles that they match. If there are
+    /// any other patterns, a title has to match at least one of them.
+    pub fn title_patterns(&self) -> impl Iterator<Item = &str> {
+        self.title_patterns
+            .as_deref()
+            .unwrap_or_default()
+            .lines()
+            .map(str::trim)
+            .filter(|pattern| !pattern.is_empty())
+    }
+    /// Whether trailers are left out
+    pub fn skip_trailers(&self) -> bool {
+        self.skip_trailers
+    }
+    /// How many days after being played an episode's download is deleted
+    pub fn delete_played_after_days(&self) -> Option<i32> {
+        self.delete_played_after_days
+    }
+    /// How many of the newest downloads to keep, deleting played episodes older than them
+    pub fn keep_latest(&self) -> Option<i32> {
+        self.keep_latest
+    }
+
+    /// Set how many of the newest unplayed episodes to download, or `None` for all of them
+    pub fn set_newest_unplayed(&mut self, count: Option<i32>) {
+        self.newest_unplayed = count;
+    }
+    /// Set the patterns that episode titles are matched against, as described for
+    /// [`DownloadRules::title_patterns`]
+    pub fn set_title_patterns<'a>(&mut self, patterns: impl IntoIterator<Item = &'a str>) {
+        let patterns: Vec<&str> = patterns
+            .into_iter()
+            .map(str::trim)
+            .filter(|pattern| !pattern.is_empty())
+            .collect();
+        self.title_patterns = Some(patterns.join("\n")).filter(|patterns| !patterns.is_empty());
+    }
+    /// Set whether trailers are left out
+    pub fn set_skip_trailers(&mut self, skip: bool) {
+        self.skip_trailers = skip;
+    }
+    /// Set how many days after being played an episode's download is deleted
+    pub fn set_delete_played_after_days(&mut self, days: Option<i32>) {
+        self.delete_played_after_days = days;
+    }
+    /// Set how many of the newest downloads to keep
+    pub fn set_keep_latest(&mut self, count: Option<i32>) {
+        self.keep_latest = count;
+    }
+
+    /// Whether `episode` is one that these rules would download
+    pub fn matches(&self, episode: &Episode) -> bool {
+        !(self.skip_trailers && episode.is_trailer()) && self.title_matches(episode.title())
+    }
+
+    fn title_matches(&self, title: &str) -> bool {
+        let title = title.to_lowercase();
+        let mut included = None;
+        for pattern in self.title_patterns() {
+            match pattern.strip_prefix('!') {
+                Some(excluded) => {
+                    if contains_pattern(&title, &excluded.trim().to_lowercase()) {
+                        return false;
+                    }
+                }
+                None => {
+                    included = Some(
+                        included.unwrap_or(false)
+                            || contains_pattern(&title, &pattern.to_lowercase()),
+                    );
+                }
+            }
+        }
+        included.unwrap_or(true)
+    }
+
+    /// Get the rules for the podcast with ID `podcast_id`
+    pub fn for_podcast(
+        con: &mut SqliteConnection,
+        podcast_id: i32,
+    ) -> QueryResult<Option<DownloadRules>> {
+        download_rules::table.find(podcast_id).first(con).optional()
+    }
+
+    /// Write these rules to the database, replacing any that the podcast already had
+    pub fn save(&self, con: &mut SqliteConnection) -> QueryResult<()> {
+        diesel::replace_into(download_rules::table)
+            .values(self)
+            .execute(con)
+            .map(|_| ())
+    }
+
+    /// Remove the rules for the podcast with ID `podcast_id`, so that nothing more is downloaded
+    /// or deleted automatically
+    pub fn delete(con: &mut SqliteConnection, podcast_id: i32) -> QueryResult<()> {
+        diesel::delete(download_rules::table.find(podcast_id))
+            .execute(con)
+            .map(|_| ())
+    }
+
+    /// Get the episodes that these rules want downloaded, but that haven't been yet, newest
+    /// first
+    pub fn wanted(&self, con: &mut SqliteConnection) -> QueryResult<Vec<Episode>> {
+        let unplayed: Vec<Episode> = episodes::table
+            .filter(episodes::podcast_id.eq(self.podcast_id))
+            .filter(episodes::removed_upstream.is_null())
+            .filter(episodes::played.is_null())
+            .order(episodes::epoch.desc())
+            .load(con)?;
+        let limit = self
+            .newest_unplayed
+            .map_or(usize::MAX, |count| usize::try_from(count).unwrap_or(0));
+        // Episodes that are already downloaded still count towards the limit
+        Ok(unplayed
+            .into_iter()
+            .filter(|episode| self.matches(episode))
+            .take(limit)
+            .filter(|episode| episode.local_uri().is_none())
+            .collect())
+    }
+
+    /// Get the downloaded episodes that these rules say should be deleted at `now`, which is a
+    /// Unix timestamp
+    pub fn expired(&self, con: &mut SqliteConnection, now: i64) -> QueryResult<Vec<Episode>> {
+        let downloaded: Vec<Episode> = episodes::table
+            .filter(episodes::podcast_id.eq(self.podcast_id))
+            .filter(episodes::local_uri.is_not_null())
+            .order(episodes::epoch.desc())
+            .load(con)?;
+        let keep_latest = self
+            .keep_latest
+            .map(|count| usize::try_from(count).unwrap_or(0));
+        let played_before = self
+            .delete_played_after_days
+            .map(|days| now - i64::from(days) * 24 * 60 * 60);
+        Ok(downloaded
+            .into_iter()
+            .enumerate()
+            .filter(|(index, episode)| {
+                let Some(played) = episode.played() else {
+                    return false;
+                };
+                if episode.is_orphaned_download() {
+                    return false;
+                }
+                matches!(keep_latest, Some(keep) if *index >= keep)
+                    || matches!(played_before, Some(before) if played < before)
+            })
+            .map(|(_, episode)| episode)
+            .collect())
+    }
+}
+
+/// Whether `text` contains `pattern`, where `*` in the pattern matches any run of characters
+fn contains_pattern(text: &str, pattern: &str) -> bool {
+    let mut parts = pattern.split('*').filter(|part| !part.is_empty());
+    let mut rest = text;
+    parts.all(|part| match rest.find(part) {
+        Some(start) => {
+            rest = &rest[start + part.len()..];
+            true
+        }
+        None => false,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::{Removed, Source};
+    use std::fs::File;
+    use std::io::BufReader;
+
+    #[test]
+    pub(crate) fn match_titles() {
+        let mut rules = DownloadRules {
+            podcast_id: 0,
+            newest_unplayed: None,
+            title_patterns: None,
+            skip_trailers: true,
+            delete_played_after_days: None,
+            keep_latest: None,
+        };
+        assert!(contains_pattern("502: live from wwdc", "live*wwdc"));
+        assert!(!contains_pattern("502: wwdc live", "live*wwdc"));
+
+        assert!(rules.title_matches("Anything at all"));
+        rules.set_title_patterns(["Interview", " Q&A ", "!rerun"]);
+        assert_eq!(rules.title_patterns().count(), 3);
+        assert!(rules.title_matches("An interview with someone"));
+        assert!(rules.title_matches("Listener q&a"));
+        assert!(!rules.title_matches("Interview (Rerun)"));
+        assert!(!rules.title_matches("Episode 12"));
+
+        rules.set_title_patterns(["!trailer"]);
+        assert!(rules.title_matches("Episode 12"));
+        rules.set_title_patterns([]);
+        assert!(rules.title_patterns.is_none());
+    }
+
+    #[test]
+    pub(crate) fn expire_played_downloads() -> Result<(), Box<dyn std::error::Error>> {
+        const DAY: i64 = 24 * 60 * 60;
+        let mut con = SqliteConnection::establish(":memory:")?;
+        crate::run_migration_on(&mut con)?;
+        let source = Source::get_or_create(&mut con, "https://atp.fm/rss")?;
+        let file = File::open("test-data/feeds/atp.xml")?;
+        let channel = rss::Channel::read_from(BufReader::new(file))?;
+        let podcast = crate::feed::index(&mut con, &source, &channel)?.podcast;
+        let episodes = Episode::for_podcast(&mut con, podcast.id(), Removed::Exclude)?;
+        let (newest, older) = (episodes[0].title(), episodes[1].title());
+        for title in [newest, older] {
+            Episode::set_local_uri(&mut con, podcast.id(), title, Some("/tmp/episode.mp3"))?;
+        }
+
+        let now = 100 * DAY;
+        let mut rules = DownloadRules::new(&podcast);
+        rules.set_newest_unplayed(Some(2));
+        rules.set_delete_played_after_days(Some(7));
+        assert!(rules.expired(&mut con, now)?.is_empty());
+        assert!(rules.wanted(&mut con)?.is_empty());
+
+        Episode::set_play_position(&mut con, podcast.id(), older, 3_600_000)?;
+        Episode::set_played(&mut con, podcast.id(), older, Some(now - 8 * DAY))?;
+        Episode::set_played(&mut con, podcast.id(), newest, Some(now - DAY))?;
+        // Playing it again starts from the beginning
+        let replayed = Episode::find(&mut con, podcast.id(), older)?.unwrap();
+        assert_eq!(replayed.play_position(), 0);
+        let expired = rules.expired(&mut con, now)?;
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].title(), older);
+        // Played episodes no longer count towards the newest unplayed
+        let wanted = rules.wanted(&mut con)?;
+        assert_eq!(wanted.len(), 2);
+        assert_eq!(wanted[0].title(), episodes[2].title());
+        Ok(())
+    }
+}
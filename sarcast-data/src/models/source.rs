@@ -0,0 +1,244 @@
+use super::{credentials, Credentials};
+use crate::schema::source;
+use diesel::prelude::*;
+
+/// The number of consecutive failures after which a `Source` is considered broken.
+pub const BROKEN_AFTER_FAILURES: i32 = 3;
+
+/// The delay before retrying a feed that has failed once.
+const BACKOFF_BASE_MINUTES: i64 = 15;
+/// The longest that a failing feed will be left before it is retried.
+const BACKOFF_MAX_MINUTES: i64 = 24 * 60;
+
+#[derive(Queryable, Identifiable, AsChangeset, PartialEq)]
+#[diesel(table_name = source)]
+#[diesel(treat_none_as_null = true)]
+#[derive(Clone)]
+/// Diesel Model of the source table.
+pub struct Source {
+    id: i32,
+    uri: String,
+    last_modified: Option<String>,
+    http_etag: Option<String>,
+    last_attempt: Option<chrono::NaiveDateTime>,
+    last_success: Option<chrono::NaiveDateTime>,
+    failure_count: i32,
+    last_error: Option<String>,
+    last_http_status: Option<i32>,
+}
+
+impl std::fmt::Debug for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Source")
+            .field("id", &self.id)
+            .field("uri", &credentials::redact_uri(&self.uri))
+            .field("last_modified", &self.last_modified)
+            .field("http_etag", &self.http_etag)
+            .field("last_attempt", &self.last_attempt)
+            .field("last_success", &self.last_success)
+            .field("failure_count", &self.failure_count)
+            .field("last_error", &self.last_error)
+            .field("last_http_status", &self.last_http_status)
+            .finish()
+    }
+}
+
+impl Source {
+    /// The row ID of this source
+    pub fn id(&self) -> i32 {
+        self.id
+    }
+    /// The URI of the feed
+    pub fn uri(&self) -> &str {
+        self.uri.as_ref()
+    }
+    /// The `Last-Modified` header from the last successful fetch
+    pub fn last_modified(&self) -> Option<&str> {
+        self.last_modified.as_deref()
+    }
+    /// The `ETag` header from the last successful fetch
+    pub fn http_etag(&self) -> Option<&str> {
+        self.http_etag.as_deref()
+    }
+    /// When this feed was last fetched, successfully or not
+    pub fn last_attempt(&self) -> Option<&chrono::NaiveDateTime> {
+        self.last_attempt.as_ref()
+    }
+    /// When this feed was last fetched and parsed successfully
+    pub fn last_success(&self) -> Option<&chrono::NaiveDateTime> {
+        self.last_success.as_ref()
+    }
+    /// The number of fetches that have failed since the last success
+    pub fn failure_count(&self) -> i32 {
+        self.failure_count
+    }
+    /// The error from the most recent failed fetch
+    pub fn last_error(&self) -> Option<&str> {
+        self.last_error.as_deref()
+    }
+    /// The HTTP status code from the most recent fetch
+    pub fn last_http_status(&self) -> Option<i32> {
+        self.last_http_status
+    }
+
+    /// Whether this feed has failed enough times in a row to be considered broken
+    pub fn is_broken(&self) -> bool {
+        self.failure_count >= BROKEN_AFTER_FAILURES
+    }
+
+    /// Whether the last fetch was refused for want of credentials, or because they were wrong
+    pub fn requires_auth(&self) -> bool {
+        self.failure_count > 0 && matches!(self.last_http_status, Some(401 | 403))
+    }
+
+    /// Record a successful fetch and parse of this feed.
+    ///
+    /// This resets the failure count and clears the last error.
+    pub fn record_success(&mut self, http_status: Option<u16>) {
+        let now = chrono::Utc::now().naive_utc();
+        self.last_attempt = Some(now);
+        self.last_success = Some(now);
+        self.failure_count = 0;
+        self.last_error = None;
+        self.last_http_status = http_status.map(i32::from);
+    }
+
+    /// Record a failed fetch or parse of this feed.
+    pub fn record_failure(&mut self, error: &str, http_status: Option<u16>) {
+        self.last_attempt = Some(chrono::Utc::now().naive_utc());
+        self.failure_count = self.failure_count.saturating_add(1);
+        self.last_error = Some(error.to_owned());
+        self.last_http_status = http_status.map(i32::from);
+    }
+
+    /// The earliest time that this feed should be refreshed again.
+    ///
+    /// Feeds that are failing are backed off exponentially, starting at 15 minutes and doubling
+    /// with each consecutive failure up to a day. Healthy feeds can be refreshed at any time, so
+    /// this returns `None`.
+    pub fn next_refresh(&self) -> Option<chrono::NaiveDateTime> {
+        if self.failure_count == 0 {
+            return None;
+        }
+        let shift = u32::try_from(self.failure_count - 1).unwrap_or(0).min(16);
+        let minutes = (BACKOFF_BASE_MINUTES << shift).min(BACKOFF_MAX_MINUTES);
+        self.last_attempt
+            .map(|last| last + chrono::Duration::minutes(minutes))
+    }
+
+    /// Whether this feed is allowed to be refreshed at `now`
+    pub fn is_refresh_due(&self, now: chrono::NaiveDateTime) -> bool {
+        !matches!(self.next_refresh(), Some(next) if now < next)
+    }
+
+    /// Write any changes to this source back to the database
+    pub fn save(&self, con: &mut SqliteConnection) -> QueryResult<()> {
+        diesel::update(self).set(self).execute(con).map(|_| ())
+    }
+
+    /// Get the source for `uri`, creating it if it doesn't already exist.
+    ///
+    /// Any username, password or token in `uri` is moved into the source's [`Credentials`], so
+    /// that the source itself holds no secrets. A source that was stored with them in its URI
+    /// loses them the same way.
+    pub fn get_or_create(con: &mut SqliteConnection, uri: &str) -> QueryResult<Source> {
+        let (stripped, secrets) = credentials::strip_secrets(uri);
+        con.transaction(|con| {
+            if !secrets.is_empty() {
+                let stored: i64 = source::table
+                    .filter(source::uri.eq(&stripped))
+                    .count()
+                    .get_result(con)?;
+                if stored == 0 {
+                    let _ = diesel::update(source::table.filter(source::uri.eq(uri)))
+                        .set(source::uri.eq(&stripped))
+                        .execute(con)?;
+                }
+            }
+            let _ = diesel::insert_or_ignore_into(source::table)
+                .values(NewSource {
+                    uri: stripped.clone(),
+                })
+                .execute(con)?;
+            let source: Source = source::table.filter(source::uri.eq(&stripped)).first(con)?;
+            if !secrets.is_empty() {
+                let mut credentials = Credentials::for_source(con, source.id)?
+                    .unwrap_or_else(|| Credentials::new(&source));
+                credentials.add_secrets(secrets);
+                credentials.save(con)?;
+            }
+            Ok(source)
+        })
+    }
+
+    /// Get all of the sources that have failed at least `BROKEN_AFTER_FAILURES` times in a row.
+    ///
+    /// The sources are ordered with the longest-failing first.
+    pub fn broken(con: &mut SqliteConnection) -> QueryResult<Vec<Source>> {
+        source::table
+            .filter(source::failure_count.ge(BROKEN_AFTER_FAILURES))
+            .order((source::last_success.asc(), source::failure_count.desc()))
+            .load(con)
+    }
+}
+
+///
+#[derive(Insertable)]
+#[diesel(table_name = source)]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NewSource {
+    uri: String,
+}
+
+impl NewSource {
+    ///
+    pub fn new<S: Into<String>>(uri: S) -> Self {
+        NewSource { uri: uri.into() }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn source() -> Source {
+        Source {
+            id: 0,
+            uri: "https://example.com/feed".into(),
+            last_modified: None,
+            http_etag: None,
+            last_attempt: None,
+            last_success: None,
+            failure_count: 0,
+            last_error: None,
+            last_http_status: None,
+        }
+    }
+
+    #[test]
+    pub(crate) fn failures_back_off() {
+        let mut source = source();
+        assert!(source.next_refresh().is_none());
+
+        source.record_failure("Connection refused", None);
+        let first = source.next_refresh().unwrap() - *source.last_attempt().unwrap();
+        assert_eq!(first, chrono::Duration::minutes(15));
+
+        source.record_failure("Not Found", Some(404));
+        let second = source.next_refresh().unwrap() - *source.last_attempt().unwrap();
+        assert_eq!(second, chrono::Duration::minutes(30));
+        assert_eq!(source.last_http_status(), Some(404));
+
+        for _ in 0..20 {
+            source.record_failure("Not Found", Some(404));
+        }
+        let capped = source.next_refresh().unwrap() - *source.last_attempt().unwrap();
+        assert_eq!(capped, chrono::Duration::days(1));
+        assert!(source.is_broken());
+
+        source.record_success(Some(200));
+        assert!(!source.is_broken());
+        assert!(source.last_error().is_none());
+        assert!(source.is_refresh_due(*source.last_attempt().unwrap()));
+    }
+}
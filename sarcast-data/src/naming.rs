@@ -0,0 +1,332 @@
+use crate::models::{Episode, Podcast};
+use chrono::Datelike;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+/// The template used when none has been configured: one directory per podcast, with the
+/// episodes in it sorted by date.
+pub const DEFAULT_TEMPLATE: &str = "{podcast}/{date} {title}[.{ext}]";
+
+/// The longest that a single file or directory name is allowed to be, in bytes.
+///
+/// Most filesystems allow 255, but this leaves room for collision suffixes and `.part`.
+const MAX_COMPONENT_BYTES: usize = 200;
+
+/// Names that Windows reserves for devices, which can't be used for files even with an extension
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Podcast,
+    Title,
+    Date,
+    Year,
+    Month,
+    Day,
+    Season,
+    Episode,
+    Extension,
+}
+
+impl Field {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "podcast" => Field::Podcast,
+            "title" => Field::Title,
+            "date" => Field::Date,
+            "year" => Field::Year,
+            "month" => Field::Month,
+            "day" => Field::Day,
+            "season" => Field::Season,
+            "episode" => Field::Episode,
+            "ext" => Field::Extension,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    /// A placeholder, and the width to zero pad it to
+    Field(Field, usize),
+    /// A section that is left out if any of its placeholders have no value
+    Optional(Vec<Part>),
+}
+
+/// A template for where to save downloaded episodes, relative to the downloads directory.
+///
+/// Placeholders are written in braces:
+/// - `{podcast}`: the title of the podcast
+/// - `{title}`: the title of the episode
+/// - `{date}`: the day the episode was published, as `YYYY-MM-DD`
+/// - `{year}`, `{month}`, `{day}`: parts of the publish date
+/// - `{season}`, `{episode}`: the season and episode numbers
+/// - `{ext}`: the file extension, without the leading `.`
+///
+/// Numbers can be zero padded with a width, like `{episode:3}`. Anything in square brackets is
+/// left out if any of the placeholders in it have no value, such as `[S{season}E{episode} ]`.
+/// `/` separates directories, and every placeholder is made safe to use in a file name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameTemplate {
+    parts: Vec<Part>,
+}
+
+impl Default for NameTemplate {
+    fn default() -> Self {
+        DEFAULT_TEMPLATE
+            .parse()
+            .expect("The default template is valid")
+    }
+}
+
+impl std::str::FromStr for NameTemplate {
+    type Err = String;
+    fn from_str(template: &str) -> Result<Self, Self::Err> {
+        if template.trim().is_empty() {
+            return Err("The template is empty".into());
+        }
+        if template.starts_with('/') || template.starts_with('\\') {
+            return Err("The template must be a relative path".into());
+        }
+        if template
+            .split(['/', '\\'])
+            .any(|component| component.trim() == "..")
+        {
+            return Err("The template can't refer to a parent directory".into());
+        }
+
+        let mut parts = vec![];
+        let mut optional: Option<Vec<Part>> = None;
+        let mut chars = template.chars();
+        while let Some(c) = chars.next() {
+            let part = match c {
+                '{' => {
+                    let mut placeholder = String::new();
+                    let mut closed = false;
+                    for c in chars.by_ref() {
+                        if c == '}' {
+                            closed = true;
+                            break;
+                        }
+                        placeholder.push(c);
+                    }
+                    if !closed {
+                        return Err("Unmatched '{' in the template".into());
+                    }
+                    let (name, width) = match placeholder.split_once(':') {
+                        Some((name, width)) => (
+                            name,
+                            width
+                                .parse()
+                                .map_err(|_| format!("Invalid width in {{{}}}", placeholder))?,
+                        ),
+                        None => (placeholder.as_str(), 0),
+                    };
+                    let field = Field::from_name(name.trim())
+                        .ok_or_else(|| format!("Unknown placeholder {{{}}}", placeholder))?;
+                    Part::Field(field, width)
+                }
+                '}' => return Err("Unmatched '}' in the template".into()),
+                '[' if optional.is_some() => {
+                    return Err("Optional sections can't be nested".into());
+                }
+                '[' => {
+                    optional = Some(vec![]);
+                    continue;
+                }
+                ']' => match optional.take() {
+                    Some(inner) => Part::Optional(inner),
+                    None => return Err("Unmatched ']' in the template".into()),
+                },
+                c => Part::Literal(c.to_string()),
+            };
+            match &mut optional {
+                Some(inner) => inner.push(part),
+                None => parts.push(part),
+            }
+        }
+        if optional.is_some() {
+            return Err("Unmatched '[' in the template".into());
+        }
+        Ok(NameTemplate { parts })
+    }
+}
+
+impl NameTemplate {
+    /// Work out the path to save `episode` to, relative to the downloads directory
+    pub fn render(&self, podcast: &Podcast, episode: &Episode, extension: Option<&str>) -> PathBuf {
+        // An epoch of 0 means that the feed didn't give a date we could read
+        let published = Some(episode.epoch())
+            .filter(|epoch| *epoch != 0)
+            .and_then(|epoch| chrono::DateTime::from_timestamp(epoch, 0))
+            .map(|date| date.date_naive());
+        self.render_with(|field| match field {
+            Field::Podcast => Some(podcast.title().to_owned()),
+            Field::Title => Some(episode.title().to_owned()),
+            Field::Date => published.map(|date| date.format("%Y-%m-%d").to_string()),
+            Field::Year => published.map(|date| date.year().to_string()),
+            Field::Month => published.map(|date| format!("{:02}", date.month())),
+            Field::Day => published.map(|date| format!("{:02}", date.day())),
+            Field::Season => episode.season().map(|season| season.to_string()),
+            Field::Episode => episode.episode_number().map(|number| number.to_string()),
+            Field::Extension => extension.map(str::to_owned),
+        })
+    }
+
+    fn render_with(&self, value: impl Fn(Field) -> Option<String>) -> PathBuf {
+        /// Render `parts`, giving up if any placeholder in an optional section has no value.
+        /// Placeholders outside of an optional section are just left empty.
+        fn render(
+            parts: &[Part],
+            value: &dyn Fn(Field) -> Option<String>,
+            optional: bool,
+        ) -> Option<String> {
+            let mut rendered = String::new();
+            for part in parts {
+                match part {
+                    Part::Literal(text) => rendered.push_str(text),
+                    Part::Field(field, width) => match value(*field) {
+                        Some(text) => {
+                            // Values can't be allowed to add directories of their own
+                            let text = text.replace(['/', '\\'], "_");
+                            let _ = write!(rendered, "{:0>width$}", text, width = *width);
+                        }
+                        None if optional => return None,
+                        None => {}
+                    },
+                    Part::Optional(inner) => {
+                        rendered.push_str(&render(inner, value, true).unwrap_or_default());
+                    }
+                }
+            }
+            Some(rendered)
+        }
+
+        render(&self.parts, &value, false)
+            .unwrap_or_default()
+            .split(['/', '\\'])
+            .filter(|component| !component.trim().is_empty())
+            .map(sanitize)
+            .collect()
+    }
+}
+
+/// Make `name` safe to use as a file or directory name on any common filesystem.
+///
+/// Characters that Windows, macOS or Android don't allow are replaced, leading and trailing dots
+/// and spaces are removed, reserved names are escaped, and long names are shortened while keeping
+/// their extension.
+pub fn sanitize(name: &str) -> String {
+    let mut sanitized = String::with_capacity(name.len());
+    for c in name.chars() {
+        let c = match c {
+            '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => '_',
+            c if c.is_control() => ' ',
+            c if c.is_whitespace() => ' ',
+            c => c,
+        };
+        // Collapse runs of whitespace
+        if c == ' ' && sanitized.ends_with(' ') {
+            continue;
+        }
+        sanitized.push(c);
+    }
+    let mut sanitized = sanitized.trim_matches(['.', ' ']).to_owned();
+
+    let stem = sanitized.split('.').next().unwrap_or_default().trim_end();
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+    {
+        sanitized.insert(0, '_');
+    }
+
+    if sanitized.len() > MAX_COMPONENT_BYTES {
+        let extension = sanitized
+            .rsplit_once('.')
+            .map(|(_, extension)| extension)
+            .filter(|extension| extension.len() <= 5)
+            .map(|extension| format!(".{}", extension))
+            .unwrap_or_default();
+        let mut end = MAX_COMPONENT_BYTES - extension.len();
+        while !sanitized.is_char_boundary(end) {
+            end -= 1;
+        }
+        sanitized = format!(
+            "{}{}",
+            sanitized[..end].trim_end_matches(['.', ' ']),
+            extension
+        );
+    }
+
+    if sanitized.is_empty() {
+        "_".into()
+    } else {
+        sanitized
+    }
+}
+
+/// Add a number to a path to avoid a collision, turning `name.mp3` into `name (2).mp3`
+pub fn with_suffix(path: &Path, number: u32) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{} ({}).{}", stem, number, extension.to_string_lossy()),
+        None => format!("{} ({})", stem, number),
+    };
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub(crate) fn render_download_names() -> Result<(), String> {
+        let value = |field| match field {
+            Field::Podcast => Some("Accidental Tech Podcast".to_owned()),
+            Field::Title => Some("502: AC/DC: \"Live\"?".to_owned()),
+            Field::Date => Some("2022-10-13".to_owned()),
+            Field::Episode => Some("7".to_owned()),
+            Field::Extension => Some("mp3".to_owned()),
+            _ => None,
+        };
+        let render = |template: &str| -> Result<PathBuf, String> {
+            Ok(template.parse::<NameTemplate>()?.render_with(value))
+        };
+
+        assert_eq!(
+            render(DEFAULT_TEMPLATE)?,
+            Path::new("Accidental Tech Podcast/2022-10-13 502_ AC_DC_ _Live__.mp3")
+        );
+        assert_eq!(
+            render("{podcast}/[S{season}]E{episode:3} {title}.{ext}")?,
+            Path::new("Accidental Tech Podcast/E007 502_ AC_DC_ _Live__.mp3")
+        );
+        assert_eq!(
+            render("{podcast}/{year}/[{season}/]{title}")?,
+            Path::new("Accidental Tech Podcast/502_ AC_DC_ _Live__")
+        );
+
+        assert!("{nope}".parse::<NameTemplate>().is_err());
+        assert!("[{title}".parse::<NameTemplate>().is_err());
+        assert!("{title".parse::<NameTemplate>().is_err());
+        assert!("../{title}".parse::<NameTemplate>().is_err());
+        assert!("/{title}".parse::<NameTemplate>().is_err());
+
+        assert_eq!(sanitize("  ..hidden. "), "hidden");
+        assert_eq!(sanitize("con.mp3"), "_con.mp3");
+        assert_eq!(sanitize("tab\there"), "tab here");
+        let long = sanitize(&format!("{}.mp3", "é".repeat(200)));
+        assert!(long.len() <= MAX_COMPONENT_BYTES && long.ends_with("é.mp3"));
+
+        assert_eq!(
+            with_suffix(Path::new("show/episode.mp3"), 2),
+            Path::new("show/episode (2).mp3")
+        );
+        Ok(())
+    }
+}
@@ -1,18 +1,85 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    credentials (source_id) {
+        source_id -> Integer,
+        username -> Nullable<Text>,
+        password -> Nullable<Text>,
+        query -> Nullable<Text>,
+        headers -> Nullable<Text>,
+        hosts -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    download_rules (podcast_id) {
+        podcast_id -> Integer,
+        newest_unplayed -> Nullable<Integer>,
+        title_patterns -> Nullable<Text>,
+        skip_trailers -> Bool,
+        delete_played_after_days -> Nullable<Integer>,
+        keep_latest -> Nullable<Integer>,
+    }
+}
+
+diesel::table! {
+    downloads (id) {
+        id -> Integer,
+        episode_title -> Text,
+        podcast_id -> Integer,
+        uri -> Text,
+        path -> Text,
+        state -> Text,
+        downloaded_bytes -> BigInt,
+        total_bytes -> Nullable<BigInt>,
+        attempts -> Integer,
+        next_attempt -> Nullable<Timestamp>,
+        last_error -> Nullable<Text>,
+        created -> Timestamp,
+        expected_bytes -> Nullable<BigInt>,
+        integrity -> Nullable<Text>,
+        verified -> Bool,
+        manual -> Bool,
+    }
+}
+
+diesel::table! {
+    enclosures (id) {
+        id -> Integer,
+        episode_title -> Text,
+        podcast_id -> Integer,
+        uri -> Text,
+        mime_type -> Nullable<Text>,
+        length -> Nullable<BigInt>,
+        bitrate -> Nullable<Integer>,
+        codecs -> Nullable<Text>,
+        title -> Nullable<Text>,
+        is_default -> Bool,
+        integrity -> Nullable<Text>,
+    }
+}
+
 diesel::table! {
     episodes (title, podcast_id) {
         title -> Text,
         uri -> Nullable<Text>,
         local_uri -> Nullable<Text>,
         description -> Nullable<Text>,
-        epoch -> Integer,
-        length -> Nullable<Integer>,
+        epoch -> BigInt,
+        length -> Nullable<BigInt>,
         duration -> Nullable<Integer>,
         guid -> Nullable<Text>,
-        played -> Nullable<Integer>,
+        played -> Nullable<BigInt>,
         play_position -> Integer,
         podcast_id -> Integer,
+        removed_upstream -> Nullable<Timestamp>,
+        mime_type -> Nullable<Text>,
+        file_extension -> Nullable<Text>,
+        season -> Nullable<Integer>,
+        episode_number -> Nullable<Integer>,
+        episode_type -> Nullable<Text>,
+        chapters_uri -> Nullable<Text>,
+        time_saved -> BigInt,
     }
 }
 
@@ -25,6 +92,7 @@ diesel::table! {
         image_uri -> Nullable<Text>,
         image_cached -> Timestamp,
         source_id -> Integer,
+        max_download_bytes -> Nullable<BigInt>,
     }
 }
 
@@ -34,10 +102,19 @@ diesel::table! {
         uri -> Text,
         last_modified -> Nullable<Text>,
         http_etag -> Nullable<Text>,
+        last_attempt -> Nullable<Timestamp>,
+        last_success -> Nullable<Timestamp>,
+        failure_count -> Integer,
+        last_error -> Nullable<Text>,
+        last_http_status -> Nullable<Integer>,
     }
 }
 
 diesel::allow_tables_to_appear_in_same_query!(
+    credentials,
+    download_rules,
+    downloads,
+    enclosures,
     episodes,
     podcasts,
     source,
@@ -0,0 +1,135 @@
+use crate::models::Episode;
+use crate::schema::episodes;
+use diesel::prelude::*;
+use std::collections::BTreeMap;
+
+/// How much space the downloads of one podcast take up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+    /// The ID of the podcast
+    pub podcast_id: i32,
+    /// How many of its episodes are downloaded
+    pub episodes: usize,
+    /// How many bytes they take up between them
+    pub bytes: u64,
+}
+
+/// The size of the downloaded copy of `episode`.
+///
+/// This is the size of the file if it can be read, otherwise the length that the feed gave.
+pub fn downloaded_size(episode: &Episode) -> u64 {
+    episode
+        .local_uri()
+        .and_then(|path| std::fs::metadata(path).ok())
+        .map(|metadata| metadata.len())
+        .or_else(|| {
+            episode
+                .length()
+                .and_then(|length| u64::try_from(length).ok())
+        })
+        .unwrap_or(0)
+}
+
+/// Get every downloaded episode, or just those of the podcast with ID `podcast_id`
+pub fn downloaded(
+    con: &mut SqliteConnection,
+    podcast_id: Option<i32>,
+) -> QueryResult<Vec<Episode>> {
+    let mut query = episodes::table
+        .filter(episodes::local_uri.is_not_null())
+        .into_boxed();
+    if let Some(podcast_id) = podcast_id {
+        query = query.filter(episodes::podcast_id.eq(podcast_id));
+    }
+    query.load(con)
+}
+
+/// How many bytes the downloads take up, either altogether or for the podcast with ID
+/// `podcast_id`
+pub fn used(con: &mut SqliteConnection, podcast_id: Option<i32>) -> QueryResult<u64> {
+    Ok(downloaded(con, podcast_id)?
+        .iter()
+        .map(downloaded_size)
+        .sum())
+}
+
+/// How much space each podcast's downloads take up, largest first
+pub fn usage_by_podcast(con: &mut SqliteConnection) -> QueryResult<Vec<Usage>> {
+    let mut usage: BTreeMap<i32, Usage> = BTreeMap::new();
+    for episode in downloaded(con, None)? {
+        let entry = usage.entry(episode.podcast_id()).or_insert(Usage {
+            podcast_id: episode.podcast_id(),
+            episodes: 0,
+            bytes: 0,
+        });
+        entry.episodes += 1;
+        entry.bytes += downloaded_size(&episode);
+    }
+    let mut usage: Vec<Usage> = usage.into_values().collect();
+    usage.sort_by_key(|usage| std::cmp::Reverse(usage.bytes));
+    Ok(usage)
+}
+
+/// Get the downloads that can be deleted to make room for new ones, in the order to delete them.
+///
+/// Only played episodes are included, the ones that were played longest ago first. Episodes that
+/// have been removed from their feed are left out, as the download may be the only copy left, and
+/// so is the episode titled `downloading.1` of the podcast with ID `downloading.0`, whose old copy
+/// would otherwise be deleted to make room for the new one.
+pub fn evictable(
+    con: &mut SqliteConnection,
+    podcast_id: Option<i32>,
+    downloading: Option<(i32, &str)>,
+) -> QueryResult<Vec<Episode>> {
+    let mut query = episodes::table
+        .filter(episodes::local_uri.is_not_null())
+        .filter(episodes::played.is_not_null())
+        .filter(episodes::removed_upstream.is_null())
+        .order((episodes::played.asc(), episodes::epoch.asc()))
+        .into_boxed();
+    if let Some(podcast_id) = podcast_id {
+        query = query.filter(episodes::podcast_id.eq(podcast_id));
+    }
+    if let Some((podcast_id, title)) = downloading {
+        query = query.filter(diesel::dsl::not(
+            episodes::podcast_id
+                .eq(podcast_id)
+                .and(episodes::title.eq(title)),
+        ));
+    }
+    query.load(con)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::{Removed, Source};
+    use std::fs::File;
+    use std::io::BufReader;
+
+    #[test]
+    pub(crate) fn keep_the_episode_being_downloaded() -> Result<(), Box<dyn std::error::Error>> {
+        let mut con = SqliteConnection::establish(":memory:")?;
+        crate::run_migration_on(&mut con)?;
+        let source = Source::get_or_create(&mut con, "https://atp.fm/rss")?;
+        let file = File::open("test-data/feeds/atp.xml")?;
+        let channel = rss::Channel::read_from(BufReader::new(file))?;
+        let podcast_id = crate::feed::index(&mut con, &source, &channel)?
+            .podcast
+            .id();
+        let episodes = Episode::for_podcast(&mut con, podcast_id, Removed::Exclude)?;
+        let (newest, older) = (episodes[0].title(), episodes[1].title());
+        for (played, title) in [(1, older), (2, newest)] {
+            Episode::set_local_uri(&mut con, podcast_id, title, Some("/tmp/episode.mp3"))?;
+            Episode::set_played(&mut con, podcast_id, title, Some(played))?;
+        }
+
+        let all = evictable(&mut con, Some(podcast_id), None)?;
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0].title(), older);
+        let others = evictable(&mut con, None, Some((podcast_id, older)))?;
+        assert_eq!(others.len(), 1);
+        assert_eq!(others[0].title(), newest);
+        Ok(())
+    }
+}